@@ -5,6 +5,7 @@ extern crate untrusted;
 extern crate webpki;
 
 use u2f::usb::hid::*;
+use u2f::usb::transport::*;
 use u2f::usb::*;
 use u2f::*;
 use hidapi::*;
@@ -13,7 +14,6 @@ use std::fs;
 use std::path;
 use std::io::Read;
 use std::io::Write;
-use std::time::Duration;
 
 pub fn main() {
     let path = path::Path::new("regresp.json");
@@ -26,35 +26,32 @@ pub fn main() {
     let app_param = vec![0;32];
     let challenge_param = vec![0;32];
 
-    
-
     let api = HidApi::new().unwrap();
 
     let devices = api.fido_devices();
-    
+
     for device in devices.iter() {
         println!("{:#?}", device);
     }
 
-    if let Some(device) = devices.first() {
-        let auth = authenticate(&api, &device, &challenge_param, &app_param, &reg.key_handle);
+    let auth = if let Some(device_info) = devices.first() {
+        let hid_device = api.open_path(&device_info.path).expect("open");
 
-        write_auth_response_to_file(&auth);
+        authenticate(U2fHidDevice::with_packet_size(hid_device, hidapi_packet_size(device_info)).expect("packet size"), &challenge_param, &app_param, &reg.key_handle)
     } else {
         println!("no fido device found");
         std::process::exit(1);
-    }
-}
+    };
 
-pub fn authenticate(api: &HidApi, device_info: &HidDeviceInfo, challenge_param: &[u8], app_param: &[u8], key_handle: &[u8]) -> AuthenticateResponse {
-    let hid_device = api.open_path(&device_info.path).expect("open");
+    write_auth_response_to_file(&auth);
+}
 
-    let mut device = U2fHidDevice {
-        packet_size: 64,
-        channel_id: BROADCAST_CID,
-        hid_device: hid_device,
-        u2f_info: None,
-    };
+pub fn authenticate<T: HidTransport>(mut device: U2fHidDevice<T>, challenge_param: &[u8], app_param: &[u8], key_handle: &[u8]) -> AuthenticateResponse {
+    device.set_keepalive_callback(|status| {
+        if status == KeepaliveStatus::UserPresenceNeeded {
+            println!("touch your key");
+        }
+    });
 
     device.init().expect("init");
 
@@ -68,19 +65,8 @@ pub fn authenticate(api: &HidApi, device_info: &HidDeviceInfo, challenge_param:
 
     println!("got u2f version: {:?}", version);
 
-    let auth_response;
-
-    loop {
-        match device.authenticate(&challenge_param, &app_param, key_handle) {
-            Ok(auth) => { auth_response = Some(auth); break; },
-            Err(u2f::error::Error(u2f::error::ErrorKind::UserPresenceRequired, _)) => continue,
-            Err(e) => Err(e).expect("authenticate")
-        }
-
-        std::thread::sleep(Duration::from_millis(200));
-    }
-
-    auth_response.unwrap()
+    with_user_presence(device.timeouts.user_presence, || device.authenticate(&challenge_param, &app_param, key_handle))
+        .expect("authenticate")
 }
 
 pub fn write_auth_response_to_file(auth: &AuthenticateResponse) {
@@ -0,0 +1,79 @@
+extern crate u2f;
+
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use u2f::dissect::*;
+use u2f::usb::hid::HID_REPORT_SIZE;
+use u2f::usb::pcap::open_capture;
+use u2f::usb::record::RecordedReport;
+
+/// Prints a trace of a session recorded with `RecordingTransport`, or of
+/// a pcap/pcapng capture of USB traffic.
+pub fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            println!("usage: dissect <recording|capture.pcap|capture.pcapng>");
+            std::process::exit(1);
+        },
+    };
+
+    if path.ends_with(".pcap") || path.ends_with(".pcapng") {
+        dissect_capture(&path);
+    } else {
+        dissect_recording(&path);
+    }
+}
+
+fn dissect_recording(path: &str) {
+    let file = File::open(path).expect("open");
+    let mut dissector = Dissector::new(HID_REPORT_SIZE);
+
+    for line in BufReader::new(file).lines() {
+        let line = line.expect("read");
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let report = RecordedReport::parse_line(line).expect("recorded report");
+
+        println!("{} ms", report.elapsed_millis);
+
+        for trace in dissector.trace(report.direction, &report.data) {
+            println!("  {}", trace);
+        }
+    }
+}
+
+fn dissect_capture(path: &str) {
+    let captured = open_capture(path).expect("capture");
+
+    let mut devices = captured.iter().map(|c| (c.bus, c.device)).collect::<Vec<(u16, u16)>>();
+    devices.sort();
+    devices.dedup();
+
+    for (bus, device) in devices {
+        println!("bus {} device {}", bus, device);
+
+        let reports = captured.iter()
+            .filter(|c| (c.bus, c.device) == (bus, device))
+            .map(|c| c.report.clone())
+            .collect::<Vec<RecordedReport>>();
+
+        for line in trace_recording(&reports, HID_REPORT_SIZE) {
+            println!("  {}", line);
+        }
+
+        for transaction in transactions(&reports, HID_REPORT_SIZE) {
+            if transaction.is_error() {
+                println!("  error: {:?} -> {:?}", transaction.request, transaction.response);
+            } else if transaction.response.is_none() {
+                println!("  unanswered: {:?}", transaction.request);
+            }
+        }
+    }
+}
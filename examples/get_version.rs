@@ -4,6 +4,7 @@ extern crate serde_json;
 
 use u2f::usb::hid::*;
 use u2f::usb::*;
+use u2f::usb::transport::*;
 use u2f::*;
 use hidapi::*;
 
@@ -30,12 +31,7 @@ pub fn get_version(api: &HidApi, device_info: &HidDeviceInfo) {
         //api.open(device.vendor_id, device.product_id).expect("open");
         api.open_path(&device_info.path).expect("open");
 
-    let mut device = U2fHidDevice {
-        packet_size: 64,
-        channel_id: BROADCAST_CID,
-        hid_device: hid_device,
-        u2f_info: None,
-    };
+    let mut device = U2fHidDevice::with_packet_size(hid_device, hidapi_packet_size(device_info)).expect("packet size");
 
     device.init().expect("init");
 
@@ -0,0 +1,43 @@
+extern crate u2f;
+extern crate hidapi;
+
+use std::env;
+use u2f::usb::hid::*;
+use u2f::usb::*;
+use u2f::usb::transport::*;
+use hidapi::*;
+
+/// Pings the first FIDO device with payloads from one byte up to the
+/// largest message, printing round trip times and throughput.
+pub fn main() {
+    let round_trips = env::args().nth(1).and_then(|arg| arg.parse().ok()).unwrap_or(20);
+
+    let api = HidApi::new().unwrap();
+
+    let devices = api.fido_devices();
+
+    let device_info = match devices.first() {
+        Some(device_info) => device_info,
+        None => {
+            println!("no fido device found");
+            std::process::exit(1);
+        },
+    };
+
+    let hid_device = api.open_path(&device_info.path).expect("open");
+
+    let mut device = U2fHidDevice::with_packet_size(hid_device, hidapi_packet_size(device_info)).expect("packet size");
+
+    device.init().expect("init");
+
+    println!("device initialised: chan={} {:?}", device.channel_id, device.u2f_info);
+
+    let max_len = max_message_len(device.packet_size);
+
+    for &payload_len in &[1, 57, 58, 1024, max_len] {
+        let stats = device.ping_test(payload_len, round_trips).expect("ping");
+
+        println!("{:5} bytes: min {:?} mean {:?} max {:?}, {:.0} bytes/s",
+            payload_len, stats.min, stats.mean(), stats.max, stats.bytes_per_second());
+    }
+}
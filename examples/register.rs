@@ -6,50 +6,48 @@ extern crate bytebuffer;
 extern crate webpki;
 
 use u2f::usb::hid::*;
+use u2f::usb::transport::*;
 use u2f::usb::*;
 use u2f::*;
 use hidapi::*;
 use std::fs;
 use std::path;
 use std::io::Write;
-use std::time::Duration;
-use bytebuffer::*;
 
 pub fn main() {
+    let challenge = vec![0;32];
+    let app_param = vec![0;32];
 
     let api = HidApi::new().unwrap();
 
     let devices = api.fido_devices();
-    
+
     for device in devices.iter() {
         println!("{:#?}", device);
     }
 
-    if let Some(ref device) = devices.first() {
-        let challenge = vec![0;32];
-        let app_param = vec![0;32];
+    let response = if let Some(ref device_info) = devices.first() {
+        let hid_device = api.open_path(&device_info.path).expect("open");
 
-        if let Some(response) = register(&api, device, &challenge, &app_param) {
-            response.verify(&challenge, &app_param).expect("verify");
-
-            write_response_to_file(&response, path::Path::new("regresp.json"));
-        }
+        register(U2fHidDevice::with_packet_size(hid_device, hidapi_packet_size(device_info)).expect("packet size"), &challenge, &app_param)
     } else {
         println!("no fido device found");
         std::process::exit(1);
-    }
-}
+    };
 
-pub fn register(api: &HidApi, device_info: &HidDeviceInfo, challenge_param: &[u8], app_param: &[u8]) -> Option<RegisterResponse> {
+    if let Some(response) = response {
+        response.verify(&challenge, &app_param).expect("verify");
 
-    let hid_device = api.open_path(&device_info.path).expect("open");
+        write_response_to_file(&response, path::Path::new("regresp.json"));
+    }
+}
 
-    let mut device = U2fHidDevice {
-        packet_size: 64,
-        channel_id: BROADCAST_CID,
-        hid_device: hid_device,
-        u2f_info: None,
-    };
+pub fn register<T: HidTransport>(mut device: U2fHidDevice<T>, challenge_param: &[u8], app_param: &[u8]) -> Option<RegisterResponse> {
+    device.set_keepalive_callback(|status| {
+        if status == KeepaliveStatus::UserPresenceNeeded {
+            println!("touch your key");
+        }
+    });
 
     device.init().expect("init");
 
@@ -63,19 +61,10 @@ pub fn register(api: &HidApi, device_info: &HidDeviceInfo, challenge_param: &[u8
 
     println!("got u2f version: {:?}", version);
     
-    let reg_response;
-
-    loop {
-        match device.register(challenge_param, app_param) {
-            Ok(reg) => { reg_response = Some(reg); break; },
-            Err(u2f::error::Error(u2f::error::ErrorKind::UserPresenceRequired, _)) => continue,
-            Err(e) => Err(e).expect("register")
-        }
-
-        std::thread::sleep(Duration::from_millis(200));
-    }
+    let reg_response = with_user_presence(device.timeouts.user_presence, || device.register(challenge_param, app_param))
+        .expect("register");
 
-    reg_response
+    Some(reg_response)
 }
 
 pub fn write_response_to_file(reg_response: &RegisterResponse, p: &path::Path) {
@@ -83,4 +72,4 @@ pub fn write_response_to_file(reg_response: &RegisterResponse, p: &path::Path) {
     let mut f = fs::File::create(p).expect("open");
     let mut bytes = json.bytes().collect::<Vec<u8>>();
     f.write_all(&mut bytes[..]).expect("write");
-}
\ No newline at end of file
+}
@@ -0,0 +1,32 @@
+extern crate u2f;
+
+use u2f::usb::hid::*;
+use u2f::soft::*;
+use u2f::*;
+
+/// Registers and authenticates against the software authenticator, without
+/// a token or hidapi.
+pub fn main() {
+    let challenge_param = vec![0;32];
+    let app_param = vec![0;32];
+
+    let mut device = U2fHidDevice::new(SoftHidTransport::new(SoftAuthenticator::new(b"u2f-rs example soft authenticator")));
+
+    device.init().expect("init");
+
+    println!("device initialised: chan={} {:?}", device.channel_id, device.u2f_info);
+
+    let version = device.get_version().expect("version");
+
+    println!("got u2f version: {:?}", version);
+
+    let reg = device.register(&challenge_param, &app_param).expect("register");
+
+    reg.verify(&challenge_param, &app_param).expect("verify");
+
+    println!("registered key handle {:?}", reg.key_handle);
+
+    let auth = device.authenticate(&challenge_param, &app_param, &reg.key_handle).expect("authenticate");
+
+    println!("authenticated, counter={}", auth.counter);
+}
@@ -1,7 +1,10 @@
 pub mod messages;
 
+use std::time::Duration;
 use futures::Future;
 use self::messages::*;
+use usb;
+use usb::hid::Timeouts;
 
 error_chain! {
     errors {
@@ -32,6 +35,91 @@ error_chain! {
     }
 }
 
+impl ErrorKind {
+    pub fn error_code(&self) -> ErrorCode {
+        match *self {
+            ErrorKind::BadRequest(_) => ErrorCode::BadRequest,
+            ErrorKind::ConfigurationUnsupported(_) => ErrorCode::ConfigurationUnsupported,
+            ErrorKind::DeviceIneligible(_) => ErrorCode::DeviceIneligible,
+            ErrorKind::Timeout(_) => ErrorCode::Timeout,
+            _ => ErrorCode::OtherError,
+        }
+    }
+
+    pub fn response_data(&self) -> U2fResponseData {
+        let message = match *self {
+            ErrorKind::OtherError(ref msg) |
+            ErrorKind::BadRequest(ref msg) |
+            ErrorKind::ConfigurationUnsupported(ref msg) |
+            ErrorKind::DeviceIneligible(ref msg) |
+            ErrorKind::Timeout(ref msg) => msg.clone(),
+            ref kind => Some(kind.to_string()),
+        };
+
+        U2fResponseData::Error {
+            error_code: self.error_code(),
+            error_message: message,
+        }
+    }
+}
+
+impl From<::error::Error> for Error {
+    fn from(e: ::error::Error) -> Error {
+        let timed_out = match *e.kind() {
+            ::error::ErrorKind::Timeout => true,
+            ::error::ErrorKind::HidError(usb::error::ErrorKind::Timeout) => true,
+            _ => false,
+        };
+
+        if timed_out {
+            ErrorKind::Timeout(Some(e.to_string())).into()
+        } else {
+            ErrorKind::OtherError(Some(e.to_string())).into()
+        }
+    }
+}
+
+/// Device timeouts for a request's `timeoutSeconds`, if it has one.
+pub fn timeouts(timeout_seconds: Option<u32>) -> Timeouts {
+    match timeout_seconds {
+        Some(seconds) => Timeouts::with_deadline(Duration::from_secs(seconds as u64)),
+        None => Timeouts::default(),
+    }
+}
+
+impl U2fRequest {
+    pub fn request_id(&self) -> Option<u32> {
+        match *self {
+            U2fRequest::RegisterRequest { request_id, .. } |
+            U2fRequest::SignRequest { request_id, .. } => request_id,
+        }
+    }
+
+    pub fn response_type(&self) -> U2fResponseType {
+        match *self {
+            U2fRequest::RegisterRequest { .. } => U2fResponseType::RegisterResponse,
+            U2fRequest::SignRequest { .. } => U2fResponseType::SignResponse,
+        }
+    }
+
+    /// The device timeouts to serve this request with.
+    pub fn timeouts(&self) -> Timeouts {
+        match *self {
+            U2fRequest::RegisterRequest { timeout_seconds, .. } |
+            U2fRequest::SignRequest { timeout_seconds, .. } => timeouts(timeout_seconds),
+        }
+    }
+
+    /// The response telling the caller that this request failed with `e`.
+    pub fn error_response(&self, e: &Error) -> U2fResponse {
+        U2fResponse {
+            response_type: self.response_type(),
+            response_data: e.kind().response_data(),
+            request_id: self.request_id(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct RegisterResponse {
     pub version: String,
@@ -59,3 +147,65 @@ pub trait U2f {
         timeout_seconds: Option<u32>)
         -> Box<Future<Item=SignResponse,Error=Error>>;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use usb::hid::U2fHidDevice;
+    use ::{with_user_presence, U2fDevice};
+
+    fn register_request(timeout_seconds: Option<u32>) -> U2fRequest {
+        U2fRequest::RegisterRequest {
+            app_id: None,
+            timeout_seconds: timeout_seconds,
+            request_id: Some(7),
+            register_requests: vec![],
+            registered_keys: vec![],
+        }
+    }
+
+    #[test]
+    fn test_request_timeouts() {
+        assert_eq!(register_request(None).timeouts(), Timeouts::default());
+
+        let timeouts = register_request(Some(5)).timeouts();
+        assert_eq!(timeouts.transaction, Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.user_presence, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_untouched_device_times_out() {
+        let request = register_request(Some(1));
+
+        let mut device = U2fHidDevice::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+        device.hid_device.authenticator().set_auto_presence(false);
+        device.init().unwrap();
+        device.timeouts = request.timeouts();
+
+        let result: Result<::RegisterResponse> = with_user_presence(device.timeouts.user_presence, || device.register(&[1; 32], &[2; 32]))
+            .map_err(Error::from);
+
+        match request.error_response(&result.unwrap_err()) {
+            U2fResponse {
+                response_type: U2fResponseType::RegisterResponse,
+                response_data: U2fResponseData::Error { error_code: ErrorCode::Timeout, error_message: Some(_) },
+                request_id: Some(7),
+            } => {},
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_response_data() {
+        let bad_request: Error = ErrorKind::BadRequest(Some("no challenge".to_string())).into();
+
+        assert_eq!(bad_request.kind().response_data(), U2fResponseData::Error {
+            error_code: ErrorCode::BadRequest,
+            error_message: Some("no challenge".to_string()),
+        });
+
+        let other = Error::from(::error::Error::from(::error::ErrorKind::NoDevice));
+        assert_eq!(other.kind().error_code(), ErrorCode::OtherError);
+    }
+}
@@ -0,0 +1,166 @@
+//! A description of a FIDO device for inventories: what USB says about it
+//! combined with what the device reports over U2FHID.
+
+use usb::hid::*;
+use usb::transport::*;
+use usb::filter::DeviceIdentity;
+#[cfg(feature = "hidapi")]
+use hidapi::HidDeviceInfo;
+use error::*;
+use ::{U2fDevice, U2fVersion};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceDescription {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// bcdDevice, if the platform reports it.
+    pub release_number: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Protocol and firmware versions from the INIT response.
+    pub u2f_info: Option<U2fHidDeviceInfo>,
+    pub capabilities: Option<U2fHidCapabilities>,
+    pub u2f_version: Option<U2fVersion>,
+}
+
+impl DeviceDescription {
+    /// A description with only the USB identifiers filled in.
+    pub fn new(path: &str, vendor_id: u16, product_id: u16) -> DeviceDescription {
+        DeviceDescription {
+            path: path.to_owned(),
+            vendor_id: vendor_id,
+            product_id: product_id,
+            release_number: None,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+            u2f_info: None,
+            capabilities: None,
+            u2f_version: None,
+        }
+    }
+
+    /// Fills in what `device` reports about itself, running INIT first if
+    /// it has not been.
+    pub fn query<T>(&mut self, device: &mut U2fHidDevice<T>) -> Result<()> where T: HidTransport {
+        if device.u2f_info.is_none() {
+            device.init()?;
+        }
+
+        self.u2f_info = device.u2f_info.clone();
+        self.capabilities = device.capabilities();
+
+        // devices that only speak CTAP2 have no U2F version to report
+        if !device.capabilities().map(|capabilities| capabilities.nmsg).unwrap_or(false) {
+            self.u2f_version = Some(device.get_version()?);
+        }
+
+        Ok(())
+    }
+
+    /// "major.minor.build" from the INIT response.
+    pub fn firmware_version(&self) -> Option<String> {
+        self.u2f_info.as_ref().map(|info| format!("{}.{}.{}", info.major_device_version, info.minor_device_version, info.build_device_version))
+    }
+}
+
+impl DeviceIdentity for DeviceDescription {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_ref().map(|serial| serial.as_str())
+    }
+
+    fn manufacturer(&self) -> Option<&str> {
+        self.manufacturer.as_ref().map(|manufacturer| manufacturer.as_str())
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl <'a> From<&'a HidDeviceInfo> for DeviceDescription {
+    fn from(info: &HidDeviceInfo) -> DeviceDescription {
+        DeviceDescription {
+            release_number: Some(info.release_number),
+            serial_number: info.serial_number.clone(),
+            manufacturer: info.manufacturer_string.clone(),
+            product: info.product_string.clone(),
+            .. DeviceDescription::new(&info.path, info.vendor_id, info.product_id)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl <'a> From<&'a ::usb::hidraw::HidrawDeviceInfo> for DeviceDescription {
+    fn from(info: &::usb::hidraw::HidrawDeviceInfo) -> DeviceDescription {
+        DeviceDescription {
+            serial_number: info.serial_number.clone(),
+            manufacturer: info.manufacturer_string.clone(),
+            product: info.product_string.clone(),
+            .. DeviceDescription::new(&info.path, info.vendor_id, info.product_id)
+        }
+    }
+}
+
+/// Describes every FIDO device hidapi knows of. Devices that cannot be
+/// opened or queried are still listed, with only their USB details.
+#[cfg(feature = "hidapi")]
+pub fn describe_hidapi(context: &HidContext) -> Vec<DeviceDescription> {
+    use usb::FidoExt;
+
+    context.fido_devices().iter().map(|info| {
+        let mut description = DeviceDescription::from(info);
+
+        // listed all the same, with whatever could be found out
+        let _ = context.open_u2f(info)
+            .map_err(|e| e.into())
+            .and_then(|mut device| description.query(&mut device));
+
+        description
+    }).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use serde_json;
+
+    fn description() -> DeviceDescription {
+        let mut device = U2fHidDevice::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+
+        let mut description = DeviceDescription::new("soft", 0x1050, 0x0407);
+        description.query(&mut device).unwrap();
+
+        description
+    }
+
+    #[test]
+    fn test_query_soft_device() {
+        let description = description();
+
+        assert_eq!(description.u2f_version, Some(U2fVersion::V2));
+        assert_eq!(description.firmware_version(), Some("0.1.0".to_owned()));
+        assert_eq!(description.capabilities.map(|capabilities| capabilities.wink), Some(true));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let description = description();
+
+        let json = serde_json::to_string(&description).unwrap();
+
+        assert_eq!(serde_json::from_str::<DeviceDescription>(&json).unwrap(), description);
+    }
+}
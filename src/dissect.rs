@@ -0,0 +1,686 @@
+//! Decodes U2FHID traffic layer by layer for debugging.
+//!
+//! A `Dissector` is fed the reports of a session in order, in either
+//! direction. It reports every packet as it arrives and, once a message has
+//! been reassembled, decodes it: INIT and ERROR payloads, the APDU carried
+//! by MSG, and the U2F register/authenticate/version payloads inside it.
+//! `TracingTransport` prints this for a live session.
+
+use std::collections::HashMap;
+use std::fmt;
+use bytebuffer::*;
+use enum_primitive::FromPrimitive;
+
+use usb::error::Result;
+use usb::hid::*;
+use usb::record::{Direction, RecordedReport, to_hex};
+use usb::transport::*;
+use raw::frame::{U2fCommand, U2fStatusWord, Decoder, ResponseDecoder};
+use ::{AUTH_USER_PRESENCE_CHECK, AUTH_USER_PRESENCE_ENFORCE, AUTH_DONT_ENFORCE_USER_PRESENCE};
+
+/// A command APDU as found on the wire, whatever its encoding.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApduCommand {
+    pub cla: u8,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Vec<u8>,
+    pub le: Option<usize>,
+}
+
+impl ApduCommand {
+    /// Decodes short and extended (both U2F v1.0 and v1.1 style) encodings.
+    pub fn decode(apdu: &[u8]) -> Option<ApduCommand> {
+        if apdu.len() < 4 {
+            return None;
+        }
+
+        let body = &apdu[4..];
+
+        let (data, le) = if body.is_empty() {
+            (vec![], None)
+        } else if body[0] == 0 && body.len() >= 3 {
+            let nc = ((body[1] as usize) << 8) | body[2] as usize;
+
+            if body.len() == 3 {
+                // either no data (v1.0) or only Le (v1.1)
+                (vec![], if nc == 0 { Some(65536) } else { Some(nc) })
+            } else if body.len() == 3 + nc {
+                (body[3..].to_owned(), None)
+            } else if body.len() == 3 + nc + 2 {
+                let ne = ((body[3 + nc] as usize) << 8) | body[4 + nc] as usize;
+                (body[3..3 + nc].to_owned(), Some(if ne == 0 { 65536 } else { ne }))
+            } else {
+                return None;
+            }
+        } else if body.len() == 1 {
+            (vec![], Some(if body[0] == 0 { 256 } else { body[0] as usize }))
+        } else {
+            let nc = body[0] as usize;
+
+            if body.len() == 1 + nc {
+                (body[1..].to_owned(), None)
+            } else if body.len() == 2 + nc {
+                let ne = body[1 + nc] as usize;
+                (body[1..1 + nc].to_owned(), Some(if ne == 0 { 256 } else { ne }))
+            } else {
+                return None;
+            }
+        };
+
+        Some(ApduCommand {
+            cla: apdu[0],
+            ins: apdu[1],
+            p1: apdu[2],
+            p2: apdu[3],
+            data: data,
+            le: le,
+        })
+    }
+}
+
+/// U2F raw message payloads.
+#[derive(Debug, Clone, PartialEq)]
+pub enum U2fPayload {
+    RegisterRequest {
+        challenge_param: Vec<u8>,
+        application_param: Vec<u8>,
+    },
+    RegisterResponse {
+        user_public_key: Vec<u8>,
+        key_handle: Vec<u8>,
+        attestation_cert: Vec<u8>,
+        signature: Vec<u8>,
+    },
+    AuthenticateRequest {
+        control: u8,
+        challenge_param: Vec<u8>,
+        application_param: Vec<u8>,
+        key_handle: Vec<u8>,
+    },
+    AuthenticateResponse {
+        user_presence: u8,
+        counter: u32,
+        signature: Vec<u8>,
+    },
+    VersionResponse(String),
+}
+
+/// Payload of a reassembled U2FHID message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessagePayload {
+    InitRequest {
+        nonce: Vec<u8>,
+    },
+    InitResponse {
+        nonce: Vec<u8>,
+        channel_id: u32,
+        info: U2fHidDeviceInfo,
+    },
+    Error(u8),
+    Keepalive(u8),
+    Lock(u8),
+    ApduRequest(ApduCommand, Option<U2fPayload>),
+    ApduResponse {
+        data: Vec<u8>,
+        status: u16,
+        u2f: Option<U2fPayload>,
+    },
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub direction: Direction,
+    pub channel_id: u32,
+    pub command: u8,
+    pub payload: MessagePayload,
+}
+
+#[derive(Debug, Clone)]
+pub enum Dissection {
+    Packet(Direction, HidPacket),
+    Message(Message),
+    /// A report that does not parse, or a continuation without an init.
+    Garbage(Direction, Vec<u8>),
+}
+
+struct PartialMessage {
+    command: u8,
+    len: usize,
+    data: Vec<u8>,
+}
+
+pub struct Dissector {
+    packet_size: usize,
+    pending: HashMap<(u32, bool), PartialMessage>,
+    // (ins, p1) of the last APDU sent on each channel, to decode its response
+    requests: HashMap<u32, (u8, u8)>,
+}
+
+impl Dissector {
+    pub fn new(packet_size: usize) -> Dissector {
+        Dissector {
+            packet_size: packet_size,
+            pending: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    /// Dissects the next report of the session.
+    pub fn report(&mut self, direction: Direction, report: &[u8]) -> Vec<Dissection> {
+        let mut data = ByteBuffer::from_bytes(report);
+
+        let packet = match parse_packet(&mut data, self.packet_size) {
+            Ok(packet) => packet,
+            Err(_) => return vec![Dissection::Garbage(direction, report.to_owned())],
+        };
+
+        let mut dissections = vec![Dissection::Packet(direction, packet.clone())];
+
+        let key = |channel_id| (channel_id, direction == Direction::Write);
+
+        let (channel_id, complete) = match packet {
+            HidPacket::Init(frame) => {
+                let len = ::std::cmp::min(frame.len, frame.payload.len());
+
+                self.pending.insert(key(frame.channel_id), PartialMessage {
+                    command: frame.command,
+                    len: frame.len,
+                    data: frame.payload[0..len].to_owned(),
+                });
+
+                (frame.channel_id, frame.len <= frame.payload.len())
+            },
+            HidPacket::Cont(frame) => {
+                match self.pending.get_mut(&key(frame.channel_id)) {
+                    Some(message) => {
+                        let len = ::std::cmp::min(message.len - message.data.len(), frame.payload.len());
+                        message.data.extend_from_slice(&frame.payload[0..len]);
+
+                        (frame.channel_id, message.data.len() == message.len)
+                    },
+                    None => {
+                        dissections.push(Dissection::Garbage(direction, report.to_owned()));
+                        return dissections;
+                    },
+                }
+            },
+        };
+
+        if complete {
+            let message = self.pending.remove(&key(channel_id)).expect("pending message");
+            dissections.push(Dissection::Message(self.message(direction, channel_id, message)));
+        }
+
+        dissections
+    }
+
+    fn message(&mut self, direction: Direction, channel_id: u32, message: PartialMessage) -> Message {
+        let data = message.data;
+
+        let payload = match (U2fHidCommand::from_u8(message.command), direction) {
+            (Some(U2fHidCommand::Init), Direction::Write) if data.len() == 8 => {
+                MessagePayload::InitRequest { nonce: data }
+            },
+            (Some(U2fHidCommand::Init), Direction::Read) if data.len() >= 17 => {
+                let mut buf = ByteBuffer::from_bytes(&data);
+
+                MessagePayload::InitResponse {
+                    nonce: buf.read_bytes(8),
+                    channel_id: buf.read_u32(),
+                    info: U2fHidDeviceInfo {
+                        protocol_version: buf.read_u8(),
+                        major_device_version: buf.read_u8(),
+                        minor_device_version: buf.read_u8(),
+                        build_device_version: buf.read_u8(),
+                        raw_capabilities: buf.read_u8(),
+                    },
+                }
+            },
+            (Some(U2fHidCommand::Error), _) if data.len() >= 1 => MessagePayload::Error(data[0]),
+            (Some(U2fHidCommand::Keepalive), _) if data.len() >= 1 => MessagePayload::Keepalive(data[0]),
+            (Some(U2fHidCommand::Lock), Direction::Write) if data.len() == 1 => MessagePayload::Lock(data[0]),
+            (Some(U2fHidCommand::Msg), Direction::Write) => {
+                match ApduCommand::decode(&data) {
+                    Some(apdu) => {
+                        self.requests.insert(channel_id, (apdu.ins, apdu.p1));
+
+                        let u2f = u2f_request(&apdu);
+                        MessagePayload::ApduRequest(apdu, u2f)
+                    },
+                    None => MessagePayload::Raw(data),
+                }
+            },
+            (Some(U2fHidCommand::Msg), Direction::Read) => {
+                match Decoder::decode(&mut ByteBuffer::from_bytes(&data)) {
+                    Ok(response) => {
+                        let u2f = if response.status == U2fStatusWord::NoError as u16 {
+                            self.requests.get(&channel_id).and_then(|&(ins, _)| u2f_response(ins, &response.response_data))
+                        } else {
+                            None
+                        };
+
+                        MessagePayload::ApduResponse {
+                            data: response.response_data,
+                            status: response.status,
+                            u2f: u2f,
+                        }
+                    },
+                    Err(_) => MessagePayload::Raw(data),
+                }
+            },
+            _ => MessagePayload::Raw(data),
+        };
+
+        Message {
+            direction: direction,
+            channel_id: channel_id,
+            command: message.command,
+            payload: payload,
+        }
+    }
+
+    /// Dissects the next report and renders it as trace lines.
+    pub fn trace(&mut self, direction: Direction, report: &[u8]) -> Vec<String> {
+        let mut lines = vec![];
+
+        for dissection in self.report(direction, report) {
+            lines.extend(dissection.to_string().lines().map(|line| line.to_owned()));
+        }
+
+        lines
+    }
+}
+
+fn u2f_request(apdu: &ApduCommand) -> Option<U2fPayload> {
+    let data = &apdu.data;
+
+    match apdu.ins {
+        x if x == U2fCommand::Register as u8 && data.len() == 64 => Some(U2fPayload::RegisterRequest {
+            challenge_param: data[0..32].to_owned(),
+            application_param: data[32..64].to_owned(),
+        }),
+        x if x == U2fCommand::Authenticate as u8 && data.len() >= 65 && data.len() == 65 + data[64] as usize => Some(U2fPayload::AuthenticateRequest {
+            control: apdu.p1,
+            challenge_param: data[0..32].to_owned(),
+            application_param: data[32..64].to_owned(),
+            key_handle: data[65..].to_owned(),
+        }),
+        _ => None,
+    }
+}
+
+fn u2f_response(ins: u8, data: &[u8]) -> Option<U2fPayload> {
+    match ins {
+        x if x == U2fCommand::Register as u8 => {
+            if data.len() < 67 || data[0] != 0x05 {
+                return None;
+            }
+
+            let key_handle_len = data[66] as usize;
+            let cert_start = 67 + key_handle_len;
+
+            if data.len() <= cert_start {
+                return None;
+            }
+
+            let cert_len = match der_len(&data[cert_start..]) {
+                Some(len) if cert_start + len <= data.len() => len,
+                _ => return None,
+            };
+
+            Some(U2fPayload::RegisterResponse {
+                user_public_key: data[1..66].to_owned(),
+                key_handle: data[67..cert_start].to_owned(),
+                attestation_cert: data[cert_start..cert_start + cert_len].to_owned(),
+                signature: data[cert_start + cert_len..].to_owned(),
+            })
+        },
+        x if x == U2fCommand::Authenticate as u8 && data.len() >= 5 => {
+            Some(U2fPayload::AuthenticateResponse {
+                user_presence: data[0],
+                counter: ((data[1] as u32) << 24) | ((data[2] as u32) << 16) | ((data[3] as u32) << 8) | data[4] as u32,
+                signature: data[5..].to_owned(),
+            })
+        },
+        x if x == U2fCommand::Version as u8 => String::from_utf8(data.to_owned()).ok().map(U2fPayload::VersionResponse),
+        _ => None,
+    }
+}
+
+/// Length of the DER element at the start of `data`, header included.
+fn der_len(data: &[u8]) -> Option<usize> {
+    if data.len() < 2 {
+        return None;
+    }
+
+    let len = data[1] as usize;
+
+    if len < 0x80 {
+        return Some(2 + len);
+    }
+
+    let len_bytes = len & 0x7f;
+
+    if len_bytes == 0 || len_bytes > 3 || data.len() < 2 + len_bytes {
+        return None;
+    }
+
+    let len = data[2..2 + len_bytes].iter().fold(0, |len, b| (len << 8) | *b as usize);
+
+    Some(2 + len_bytes + len)
+}
+
+fn command_name(command: u8) -> String {
+    match U2fHidCommand::from_u8(command) {
+        Some(command) => format!("{:?}", command),
+        None if command >= VENDOR_COMMAND_FIRST => format!("Vendor({:#04x})", command),
+        None => format!("Unknown({:#04x})", command),
+    }
+}
+
+fn arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Write => ">",
+        Direction::Read => "<",
+    }
+}
+
+impl fmt::Display for Dissection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Dissection::Packet(direction, HidPacket::Init(ref frame)) => {
+                write!(f, "{} {:08x} init {} len={}", arrow(direction), frame.channel_id, command_name(frame.command), frame.len)
+            },
+            Dissection::Packet(direction, HidPacket::Cont(ref frame)) => {
+                write!(f, "{} {:08x} cont seq={}", arrow(direction), frame.channel_id, frame.seq)
+            },
+            Dissection::Garbage(direction, ref data) => {
+                write!(f, "{} ?? {}", arrow(direction), to_hex(data))
+            },
+            Dissection::Message(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl fmt::Display for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arrow = arrow(self.direction);
+
+        writeln!(f, "{} {:08x} {}", arrow, self.channel_id, command_name(self.command))?;
+
+        match self.payload {
+            MessagePayload::InitRequest { ref nonce } => {
+                write!(f, "{}   nonce {}", arrow, to_hex(nonce))
+            },
+            MessagePayload::InitResponse { ref nonce, channel_id, ref info } => {
+                writeln!(f, "{}   nonce {}", arrow, to_hex(nonce))?;
+                writeln!(f, "{}   channel {:08x}", arrow, channel_id)?;
+                writeln!(f, "{}   protocol {} device {}.{}.{}", arrow, info.protocol_version,
+                    info.major_device_version, info.minor_device_version, info.build_device_version)?;
+                write!(f, "{}   capabilities {:?}", arrow, info.capabilities())
+            },
+            MessagePayload::Error(code) => match U2fHidErrorCode::from_u8(code) {
+                Some(error) => write!(f, "{}   error {:?}", arrow, error),
+                None => write!(f, "{}   error {:#04x}", arrow, code),
+            },
+            MessagePayload::Keepalive(status) => match KeepaliveStatus::from_u8(status) {
+                Some(status) => write!(f, "{}   status {:?}", arrow, status),
+                None => write!(f, "{}   status {:#04x}", arrow, status),
+            },
+            MessagePayload::Lock(seconds) => write!(f, "{}   {} seconds", arrow, seconds),
+            MessagePayload::ApduRequest(ref apdu, ref u2f) => {
+                write!(f, "{}   apdu cla={:02x} ins={:02x} p1={:02x} p2={:02x} lc={} le={:?}", arrow,
+                    apdu.cla, apdu.ins, apdu.p1, apdu.p2, apdu.data.len(), apdu.le)?;
+
+                match *u2f {
+                    Some(ref u2f) => write!(f, "\n{}", U2fPayloadLines(arrow, u2f)),
+                    None if !apdu.data.is_empty() => write!(f, "\n{}   data {}", arrow, to_hex(&apdu.data)),
+                    None => Ok(()),
+                }
+            },
+            MessagePayload::ApduResponse { ref data, status, ref u2f } => {
+                match U2fStatusWord::from_u16(status) {
+                    Some(status_word) => write!(f, "{}   status {:04x} {:?}", arrow, status, status_word)?,
+                    None => write!(f, "{}   status {:04x}", arrow, status)?,
+                }
+
+                match *u2f {
+                    Some(ref u2f) => write!(f, "\n{}", U2fPayloadLines(arrow, u2f)),
+                    None if !data.is_empty() => write!(f, "\n{}   data {}", arrow, to_hex(data)),
+                    None => Ok(()),
+                }
+            },
+            MessagePayload::Raw(ref data) => write!(f, "{}   data {}", arrow, to_hex(data)),
+        }
+    }
+}
+
+struct U2fPayloadLines<'a>(&'static str, &'a U2fPayload);
+
+impl <'a> fmt::Display for U2fPayloadLines<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let arrow = self.0;
+
+        match *self.1 {
+            U2fPayload::RegisterRequest { ref challenge_param, ref application_param } => {
+                writeln!(f, "{}   register", arrow)?;
+                writeln!(f, "{}     challenge {}", arrow, to_hex(challenge_param))?;
+                write!(f, "{}     application {}", arrow, to_hex(application_param))
+            },
+            U2fPayload::RegisterResponse { ref user_public_key, ref key_handle, ref attestation_cert, ref signature } => {
+                writeln!(f, "{}   register", arrow)?;
+                writeln!(f, "{}     public key {}", arrow, to_hex(user_public_key))?;
+                writeln!(f, "{}     key handle {}", arrow, to_hex(key_handle))?;
+                writeln!(f, "{}     certificate {} bytes", arrow, attestation_cert.len())?;
+                write!(f, "{}     signature {}", arrow, to_hex(signature))
+            },
+            U2fPayload::AuthenticateRequest { control, ref challenge_param, ref application_param, ref key_handle } => {
+                let control_name = match control {
+                    AUTH_USER_PRESENCE_CHECK => "check-only",
+                    AUTH_USER_PRESENCE_ENFORCE => "enforce-user-presence-and-sign",
+                    AUTH_DONT_ENFORCE_USER_PRESENCE => "dont-enforce-user-presence-and-sign",
+                    _ => "unknown",
+                };
+
+                writeln!(f, "{}   authenticate {}", arrow, control_name)?;
+                writeln!(f, "{}     challenge {}", arrow, to_hex(challenge_param))?;
+                writeln!(f, "{}     application {}", arrow, to_hex(application_param))?;
+                write!(f, "{}     key handle {}", arrow, to_hex(key_handle))
+            },
+            U2fPayload::AuthenticateResponse { user_presence, counter, ref signature } => {
+                writeln!(f, "{}   authenticate", arrow)?;
+                writeln!(f, "{}     user presence {}", arrow, user_presence)?;
+                writeln!(f, "{}     counter {}", arrow, counter)?;
+                write!(f, "{}     signature {}", arrow, to_hex(signature))
+            },
+            U2fPayload::VersionResponse(ref version) => write!(f, "{}   version {}", arrow, version),
+        }
+    }
+}
+
+/// A request and what the device answered it with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    /// `None` for a message from the device that nothing asked for.
+    pub request: Option<Message>,
+    /// Status bytes of the keepalives sent while the request was processed.
+    pub keepalives: Vec<u8>,
+    /// The reply, or an ERROR message. `None` if the session ends first.
+    pub response: Option<Message>,
+}
+
+impl Transaction {
+    /// Whether the device answered with an ERROR or a failing status word.
+    pub fn is_error(&self) -> bool {
+        match self.response {
+            Some(Message { payload: MessagePayload::Error(_), .. }) => true,
+            Some(Message { payload: MessagePayload::ApduResponse { status, .. }, .. }) => status != U2fStatusWord::NoError as u16,
+            _ => false,
+        }
+    }
+}
+
+/// Pairs the messages of a session into transactions, in request order.
+/// A request left unanswered when the next one is sent on its channel stays
+/// without a response. Reports that do not parse are left out;
+/// `trace_recording` shows them.
+pub fn transactions(reports: &[RecordedReport], packet_size: usize) -> Vec<Transaction> {
+    let mut dissector = Dissector::new(packet_size);
+    let mut transactions: Vec<Transaction> = vec![];
+    // index in `transactions` of the request awaiting a reply on each channel
+    let mut open: HashMap<u32, usize> = HashMap::new();
+
+    for report in reports.iter().filter(|report| !report.is_timeout()) {
+        for dissection in dissector.report(report.direction, &report.data) {
+            let message = match dissection {
+                Dissection::Message(message) => message,
+                Dissection::Garbage(..) | Dissection::Packet(..) => continue,
+            };
+
+            if message.direction == Direction::Write {
+                open.insert(message.channel_id, transactions.len());
+                transactions.push(Transaction {
+                    request: Some(message),
+                    keepalives: vec![],
+                    response: None,
+                });
+                continue;
+            }
+
+            match (open.get(&message.channel_id).cloned(), message.payload.clone()) {
+                (Some(index), MessagePayload::Keepalive(status)) => {
+                    transactions[index].keepalives.push(status);
+                },
+                (Some(index), _) => {
+                    open.remove(&message.channel_id);
+                    transactions[index].response = Some(message);
+                },
+                (None, _) => {
+                    transactions.push(Transaction {
+                        request: None,
+                        keepalives: vec![],
+                        response: Some(message),
+                    });
+                },
+            }
+        }
+    }
+
+    transactions
+}
+
+/// Dissects every report of a recorded session into trace lines. Recorded
+/// timeouts carry no data and are left out.
+pub fn trace_recording(reports: &[RecordedReport], packet_size: usize) -> Vec<String> {
+    let mut dissector = Dissector::new(packet_size);
+
+    reports.iter()
+        .filter(|report| !report.is_timeout())
+        .flat_map(|report| dissector.trace(report.direction, &report.data))
+        .collect()
+}
+
+/// Prints a trace of every report passing through to `transport`.
+pub struct TracingTransport<T> {
+    transport: T,
+    dissector: ::std::sync::Mutex<Dissector>,
+}
+
+impl <T> TracingTransport<T> where T: HidTransport {
+    pub fn new(transport: T, packet_size: usize) -> TracingTransport<T> {
+        TracingTransport {
+            transport: transport,
+            dissector: ::std::sync::Mutex::new(Dissector::new(packet_size)),
+        }
+    }
+
+    fn trace(&self, direction: Direction, report: &[u8]) {
+        for line in self.dissector.lock().expect("dissector lock").trace(direction, report) {
+            println!("{}", line);
+        }
+    }
+}
+
+impl <T> HidTransport for TracingTransport<T> where T: HidTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        self.trace(Direction::Write, report);
+        self.transport.write_report(report)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let bytes = self.transport.read_report(report, timeout_millis)?;
+
+        if bytes != 0 {
+            self.trace(Direction::Read, &report[0..bytes]);
+        }
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use usb::record::*;
+    use ::U2fDevice;
+
+    #[test]
+    fn test_dissect_register_session() {
+        let soft = SoftHidTransport::new(SoftAuthenticator::new(&[7; 32]));
+
+        let mut device = U2fHidDevice::new(RecordingTransport::new(soft, vec![]));
+        device.init().unwrap();
+        let response = device.register(&[1; 32], &[2; 32]).unwrap();
+        let (_, log) = device.hid_device.into_inner();
+
+        let reports = String::from_utf8(log).unwrap().lines()
+            .map(|line| RecordedReport::parse_line(line).unwrap())
+            .collect::<Vec<RecordedReport>>();
+
+        let mut dissector = Dissector::new(HID_REPORT_SIZE);
+
+        let messages = reports.iter()
+            .flat_map(|report| dissector.report(report.direction, &report.data))
+            .filter_map(|dissection| match dissection {
+                Dissection::Message(message) => Some(message.payload),
+                _ => None,
+            })
+            .collect::<Vec<MessagePayload>>();
+
+        assert_eq!(messages.len(), 4);
+
+        match messages[2] {
+            MessagePayload::ApduRequest(ref apdu, Some(U2fPayload::RegisterRequest { ref challenge_param, .. })) => {
+                assert_eq!(apdu.ins, U2fCommand::Register as u8);
+                assert_eq!(challenge_param, &vec![1; 32]);
+            },
+            ref other => panic!("unexpected message: {:?}", other),
+        }
+
+        match messages[3] {
+            MessagePayload::ApduResponse { status: 0x9000, u2f: Some(U2fPayload::RegisterResponse { ref key_handle, ref attestation_cert, .. }), .. } => {
+                assert_eq!(key_handle, &response.key_handle);
+                assert_eq!(attestation_cert, &response.attestation_cert);
+            },
+            ref other => panic!("unexpected message: {:?}", other),
+        }
+
+        let trace = trace_recording(&reports, HID_REPORT_SIZE);
+        assert!(trace.iter().any(|line| line.contains("status 9000 NoError")));
+    }
+
+    #[test]
+    fn test_decode_apdu_encodings() {
+        let short = ApduCommand::decode(&[0, 3, 0, 0, 0]).unwrap();
+        assert_eq!((short.ins, short.data.len(), short.le), (3, 0, Some(256)));
+
+        let extended = ApduCommand::decode(&[0, 2, 7, 0, 0, 0, 2, 0xaa, 0xbb, 0, 0]).unwrap();
+        assert_eq!((extended.p1, extended.data, extended.le), (7, vec![0xaa, 0xbb], Some(65536)));
+
+        assert!(ApduCommand::decode(&[0, 2, 7]).is_none());
+    }
+}
@@ -47,5 +47,25 @@ error_chain! {
             description("user presence required")
             display("user presence required")
         }
+
+        Timeout {
+            description("timed out")
+            display("timed out")
+        }
+
+        NoDevice {
+            description("no device available")
+            display("no device available")
+        }
+
+        Cancelled {
+            description("cancelled")
+            display("cancelled")
+        }
+
+        WorkerStopped {
+            description("device worker stopped")
+            display("device worker stopped")
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,726 @@
+//! Non-blocking device operations as futures.
+//!
+//! `AsyncDevice` runs commands without blocking the calling thread. A
+//! `Poller` thread reads the reports of pending commands, blocking on their
+//! devices for up to `CANCEL_POLL_MILLIS` at a time, and wakes their futures
+//! up to process them; it also runs the timers behind retries and waits for
+//! user presence. One poller thread serves any number of devices.
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use bytebuffer::*;
+use enum_primitive::FromPrimitive;
+use futures::{future, Async, Future, Poll};
+use futures::future::Loop;
+use futures::task::{self, Task};
+
+use usb;
+use usb::hid::*;
+use usb::transport::*;
+use raw::frame::*;
+use error::*;
+use ::{U2fVersion, RegisterResponse, AuthenticateResponse, USER_PRESENCE_POLL_MILLIS, AUTH_USER_PRESENCE_ENFORCE};
+use ::{register_apdu, parse_register_response, authenticate_apdu, parse_authenticate_response, version_apdu, parse_version, apdu_error};
+
+/// A read the poller makes for a pending command, and the task to wake up
+/// once it has returned.
+struct Watch {
+    // reads one report, blocking for at most the given milliseconds
+    read: Box<FnMut(i32) + Send>,
+    task: Task,
+}
+
+struct PollerState {
+    timers: Vec<(Instant, Task)>,
+    watches: VecDeque<Watch>,
+    stopped: bool,
+}
+
+struct PollerShared {
+    state: Mutex<PollerState>,
+    changed: Condvar,
+}
+
+/// Stops the poller thread once the last `Poller` has been dropped.
+struct PollerHandle {
+    shared: Arc<PollerShared>,
+}
+
+impl Drop for PollerHandle {
+    fn drop(&mut self) {
+        self.shared.state.lock().expect("poller lock").stopped = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Reads for pending futures and wakes them up, see the module docs.
+#[derive(Clone)]
+pub struct Poller {
+    handle: Arc<PollerHandle>,
+}
+
+impl Poller {
+    /// Creates a poller. Its thread exits once the poller and every future
+    /// using it have been dropped.
+    pub fn new() -> Poller {
+        let shared = Arc::new(PollerShared {
+            state: Mutex::new(PollerState {
+                timers: vec![],
+                watches: VecDeque::new(),
+                stopped: false,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let thread_shared = shared.clone();
+        thread::spawn(move || Self::run(&thread_shared));
+
+        Poller {
+            handle: Arc::new(PollerHandle {
+                shared: shared,
+            }),
+        }
+    }
+
+    fn run(shared: &PollerShared) {
+        let mut state = shared.state.lock().expect("poller lock");
+
+        while !state.stopped {
+            let now = Instant::now();
+
+            let (due, timers): (Vec<(Instant, Task)>, Vec<(Instant, Task)>) = mem::replace(&mut state.timers, vec![])
+                .into_iter()
+                .partition(|&(at, _)| at <= now);
+            state.timers = timers;
+
+            let next_timer = state.timers.iter().map(|&(at, _)| at).min();
+            let watch = state.watches.pop_front();
+
+            if due.is_empty() && watch.is_none() {
+                // sleep until a timer is due or something is scheduled
+                state = match next_timer {
+                    Some(at) => shared.changed.wait_timeout(state, at - now).expect("poller lock").0,
+                    None => shared.changed.wait(state).expect("poller lock"),
+                };
+                continue;
+            }
+
+            // devices waiting to be read take turns, and no timer is missed
+            let mut timeout = CANCEL_POLL_MILLIS / (state.watches.len() as i32 + 1);
+            if let Some(at) = next_timer {
+                timeout = cmp::min(timeout, duration_millis(at - now));
+            }
+
+            drop(state);
+
+            for (_, task) in due {
+                task.notify();
+            }
+
+            if let Some(mut watch) = watch {
+                (watch.read)(cmp::max(timeout, 1));
+                watch.task.notify();
+            }
+
+            state = shared.state.lock().expect("poller lock");
+        }
+    }
+
+    /// Has the current task polled again at `at`.
+    fn schedule_at(&self, at: Instant) {
+        let shared = &self.handle.shared;
+
+        shared.state.lock().expect("poller lock").timers.push((at, task::current()));
+        shared.changed.notify_one();
+    }
+
+    /// Has `read` called on the poller thread, and then the current task
+    /// polled again.
+    fn watch(&self, read: Box<FnMut(i32) + Send>) {
+        let shared = &self.handle.shared;
+
+        shared.state.lock().expect("poller lock").watches.push_back(Watch {
+            read: read,
+            task: task::current(),
+        });
+        shared.changed.notify_one();
+    }
+
+    /// A future that resolves once `duration` has passed.
+    pub fn delay(&self, duration: Duration) -> Delay {
+        Delay {
+            poller: self.clone(),
+            deadline: Instant::now() + duration,
+        }
+    }
+}
+
+fn duration_millis(duration: Duration) -> i32 {
+    cmp::min(duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000, i32::max_value() as u64) as i32
+}
+
+pub struct Delay {
+    poller: Poller,
+    deadline: Instant,
+}
+
+impl Future for Delay {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<(), Error> {
+        if Instant::now() >= self.deadline {
+            return Ok(Async::Ready(()));
+        }
+
+        self.poller.schedule_at(self.deadline);
+
+        Ok(Async::NotReady)
+    }
+}
+
+/// State shared by the commands of one device.
+struct DeviceShared {
+    // whether a command holds the device
+    busy: AtomicBool,
+    // commands waiting for it to be released
+    waiting: Mutex<Vec<Task>>,
+    // whether the poller has a read queued for the command in flight
+    watched: AtomicBool,
+    // held while reading, so that the poller and a command starting or
+    // recovering never read at the same time
+    reading: Mutex<()>,
+    // what the poller read for the command in flight
+    reports: Mutex<VecDeque<usb::error::Result<Vec<u8>>>>,
+}
+
+/// A `U2fHidDevice` whose operations return futures.
+///
+/// Commands are sent one at a time: a future started while another command
+/// is in flight waits for it to finish. The poller reads the device from its
+/// own thread, so the transport must be `Sync`.
+pub struct AsyncDevice<T> {
+    device: Arc<U2fHidDevice<T>>,
+    shared: Arc<DeviceShared>,
+    poller: Poller,
+}
+
+impl <T> Clone for AsyncDevice<T> {
+    fn clone(&self) -> AsyncDevice<T> {
+        AsyncDevice {
+            device: self.device.clone(),
+            shared: self.shared.clone(),
+            poller: self.poller.clone(),
+        }
+    }
+}
+
+impl <T> AsyncDevice<T> where T: HidTransport + Send + Sync + 'static {
+    /// Takes over `device`, which must already be initialised.
+    pub fn new(device: U2fHidDevice<T>, poller: &Poller) -> AsyncDevice<T> {
+        AsyncDevice {
+            device: Arc::new(device),
+            shared: Arc::new(DeviceShared {
+                busy: AtomicBool::new(false),
+                waiting: Mutex::new(vec![]),
+                watched: AtomicBool::new(false),
+                reading: Mutex::new(()),
+                reports: Mutex::new(VecDeque::new()),
+            }),
+            poller: poller.clone(),
+        }
+    }
+
+    pub fn device(&self) -> &U2fHidDevice<T> {
+        &self.device
+    }
+
+    /// Sends `command` with `payload` and resolves to the response payload,
+    /// retrying according to the device's `retry_policy` and recovering the
+    /// channel after a failure like `U2fHidDevice::command`.
+    pub fn command(&self, command: U2fHidCommand, payload: &[u8]) -> CommandFuture<T> {
+        CommandFuture {
+            device: self.device.clone(),
+            shared: self.shared.clone(),
+            poller: self.poller.clone(),
+            command: command as u8,
+            request: payload.to_owned(),
+            state: CommandState::Queued,
+            claimed: false,
+            attempt: 1,
+            backoff: self.device.retry_policy.initial_backoff,
+            deadline: None,
+        }
+    }
+
+    /// Pings and checks that the device echoes the payload unchanged.
+    pub fn ping(&self) -> Box<Future<Item=(), Error=Error>> {
+        let payload = vec![0];
+
+        Box::new(self.command(U2fHidCommand::Ping, &payload)
+            .and_then(move |echoed| check_echo(&payload, &echoed))
+            .map_err(|e| e.into()))
+    }
+
+    /// Winks, if the device can.
+    pub fn wink(&self) -> Box<Future<Item=(), Error=Error>> {
+        if !self.device.capabilities().map(|capabilities| capabilities.wink).unwrap_or(true) {
+            return Box::new(future::ok(()));
+        }
+
+        Box::new(self.command(U2fHidCommand::Wink, &[]).map(|_| ()).map_err(|e| e.into()))
+    }
+
+    pub fn get_version(&self) -> Box<Future<Item=U2fVersion, Error=Error>> {
+        Box::new(self.apdu(version_apdu())
+            .map_err(|e| e.into())
+            .and_then(|response| parse_version(&response.response_data)))
+    }
+
+    /// Registers once the user touches the device, or fails with `Timeout`
+    /// after the device's `timeouts.user_presence`.
+    pub fn register(&self, challenge_param: &[u8], application_param: &[u8]) -> Box<Future<Item=RegisterResponse, Error=Error>> {
+        let request = match register_apdu(challenge_param, application_param) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let device = self.clone();
+
+        self.with_user_presence(move || {
+            Box::new(device.apdu(request.clone())
+                .map_err(apdu_error)
+                .and_then(|response| parse_register_response(&response.response_data)))
+        })
+    }
+
+    /// Authenticates once the user touches the device, or fails with
+    /// `Timeout` after the device's `timeouts.user_presence`.
+    pub fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Box<Future<Item=AuthenticateResponse, Error=Error>> {
+        let request = match authenticate_apdu(AUTH_USER_PRESENCE_ENFORCE, challenge_param, application_param, key_handle) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+
+        let device = self.clone();
+
+        self.with_user_presence(move || {
+            Box::new(device.apdu(request.clone())
+                .map_err(apdu_error)
+                .and_then(|response| parse_authenticate_response(&response.response_data)))
+        })
+    }
+
+    fn apdu(&self, request: CommandAPDU) -> Box<Future<Item=ResponseAPDU, Error=usb::error::Error>> {
+        let mut bb = ByteBuffer::new();
+
+        if let Err(e) = ExtendedEncoderV1::encode(&mut bb, request) {
+            return Box::new(future::err(e.into()));
+        }
+
+        Box::new(self.command(U2fHidCommand::Msg, &bb.to_bytes())
+            .and_then(|payload| decode_apdu_response(&mut ByteBuffer::from_bytes(&payload))))
+    }
+
+    /// Repeats the operation made by `op` while it fails with
+    /// `UserPresenceRequired`, like `::with_user_presence`.
+    fn with_user_presence<R, F>(&self, op: F) -> Box<Future<Item=R, Error=Error>>
+        where R: 'static, F: Fn() -> Box<Future<Item=R, Error=Error>> + 'static
+    {
+        let poller = self.poller.clone();
+        let deadline = Instant::now() + self.device.timeouts.user_presence;
+
+        Box::new(future::loop_fn((), move |_| {
+            let poller = poller.clone();
+
+            op().then(move |result| -> Box<Future<Item=Loop<R, ()>, Error=Error>> {
+                match result {
+                    Err(Error(ErrorKind::UserPresenceRequired, _)) => {
+                        if Instant::now() >= deadline {
+                            return Box::new(future::err(ErrorKind::Timeout.into()));
+                        }
+
+                        Box::new(poller.delay(Duration::from_millis(USER_PRESENCE_POLL_MILLIS)).map(|_| Loop::Continue(())))
+                    },
+                    result => Box::new(future::result(result.map(Loop::Break))),
+                }
+            })
+        }))
+    }
+}
+
+enum CommandState {
+    Queued,
+    Pending(ResponseReader),
+    Backoff(Instant),
+    Done,
+}
+
+/// A command in flight, see `AsyncDevice::command`. Dropping it before it
+/// completes cancels the request.
+pub struct CommandFuture<T> where T: HidTransport {
+    device: Arc<U2fHidDevice<T>>,
+    shared: Arc<DeviceShared>,
+    poller: Poller,
+    command: u8,
+    request: Vec<u8>,
+    state: CommandState,
+    // whether we hold the device's `busy` flag
+    claimed: bool,
+    attempt: u32,
+    backoff: Duration,
+    // of the whole transaction, set by the first attempt
+    deadline: Option<Instant>,
+}
+
+impl <T> CommandFuture<T> where T: HidTransport {
+    fn start(&mut self) -> usb::error::Result<()> {
+        if let (Some(capabilities), Some(command)) = (self.device.capabilities(), U2fHidCommand::from_u8(self.command)) {
+            if !capabilities.supports(command) {
+                bail!(usb::error::ErrorKind::UnsupportedCommand(command));
+            }
+        }
+
+        let _reading = self.shared.reading.lock().expect("reading lock");
+        self.shared.reports.lock().expect("reports lock").clear();
+
+        let reader = if self.attempt == 1 {
+            self.device.start_request(self.command, &self.request)?
+        } else {
+            self.device.start_request_until(self.command, &self.request, self.deadline)?
+        };
+
+        self.deadline = reader.deadline();
+        self.state = CommandState::Pending(reader);
+
+        Ok(())
+    }
+
+    fn poll_response(&self, reader: &mut ResponseReader) -> usb::error::Result<Option<Vec<u8>>> {
+        let mut reports = self.shared.reports.lock().expect("reports lock");
+
+        self.device.poll_response_from(reader, |report| {
+            match reports.pop_front() {
+                Some(Ok(data)) => {
+                    let len = cmp::min(data.len(), report.len());
+                    report[0..len].copy_from_slice(&data[0..len]);
+                    Ok(len)
+                },
+                Some(Err(e)) => Err(e),
+                None => Ok(0),
+            }
+        })
+    }
+
+    /// Fails the command, recovering the channel first if `e` may have left
+    /// it out of step, as `U2fHidDevice::command` does.
+    fn fail<R>(&mut self, e: usb::error::Error) -> Poll<R, usb::error::Error> {
+        if e.kind().needs_resync() {
+            let recovered = {
+                let _reading = self.shared.reading.lock().expect("reading lock");
+                self.shared.reports.lock().expect("reports lock").clear();
+
+                self.device.recover(self.deadline)
+            };
+
+            // a reassigned channel needs the caller's attention more than
+            // the error that led to it
+            if let Err(reassigned) = recovered {
+                return self.finish(Err(reassigned));
+            }
+        }
+
+        self.finish(Err(e))
+    }
+
+    fn finish<R>(&mut self, result: usb::error::Result<R>) -> Poll<R, usb::error::Error> {
+        self.state = CommandState::Done;
+        self.release();
+
+        result.map(Async::Ready)
+    }
+
+    /// Gives up the device's `busy` flag, if we hold it, to the commands
+    /// waiting for it.
+    fn release(&mut self) {
+        if self.claimed {
+            self.claimed = false;
+            self.shared.busy.store(false, Ordering::SeqCst);
+
+            for task in mem::replace(&mut *self.shared.waiting.lock().expect("waiting lock"), vec![]) {
+                task.notify();
+            }
+        }
+    }
+}
+
+impl <T> CommandFuture<T> where T: HidTransport + Send + Sync + 'static {
+    /// Has the poller read the device for us, unless it already is.
+    fn watch(&self) {
+        if self.shared.watched.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        let device = self.device.clone();
+        let shared = self.shared.clone();
+        let mut report = vec![0; device.packet_size];
+
+        self.poller.watch(Box::new(move |timeout| {
+            {
+                let _reading = shared.reading.lock().expect("reading lock");
+
+                let read = match device.hid_device.read_report(&mut report, timeout) {
+                    Ok(0) => None,
+                    Ok(bytes) => Some(Ok(report[0..bytes].to_owned())),
+                    Err(e) => Some(Err(e)),
+                };
+
+                if let Some(read) = read {
+                    shared.reports.lock().expect("reports lock").push_back(read);
+                }
+            }
+
+            shared.watched.store(false, Ordering::SeqCst);
+        }));
+    }
+}
+
+impl <T> Future for CommandFuture<T> where T: HidTransport + Send + Sync + 'static {
+    type Item = Vec<u8>;
+    type Error = usb::error::Error;
+
+    fn poll(&mut self) -> Poll<Vec<u8>, usb::error::Error> {
+        loop {
+            match mem::replace(&mut self.state, CommandState::Done) {
+                CommandState::Queued => {
+                    // registered first, so that a release in between still
+                    // wakes us up
+                    self.shared.waiting.lock().expect("waiting lock").push(task::current());
+
+                    if self.shared.busy.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                        // another command is in flight
+                        self.state = CommandState::Queued;
+                        return Ok(Async::NotReady);
+                    }
+
+                    self.claimed = true;
+
+                    if let Err(e) = self.start() {
+                        return self.fail(e);
+                    }
+                },
+                CommandState::Backoff(until) => {
+                    if Instant::now() < until {
+                        self.state = CommandState::Backoff(until);
+                        self.poller.schedule_at(until);
+                        return Ok(Async::NotReady);
+                    }
+
+                    if let Err(e) = self.start() {
+                        return self.fail(e);
+                    }
+                },
+                CommandState::Pending(mut reader) => {
+                    match self.poll_response(&mut reader) {
+                        Ok(Some(payload)) => return self.finish(Ok(payload)),
+                        Ok(None) => {
+                            self.state = CommandState::Pending(reader);
+                            self.watch();
+                            return Ok(Async::NotReady);
+                        },
+                        Err(ref e) if e.kind().is_retryable() && self.attempt < self.device.retry_policy.max_attempts => {
+                            let until = Instant::now() + self.backoff;
+
+                            // no attempt could finish in time
+                            if self.deadline.map(|deadline| until >= deadline).unwrap_or(false) {
+                                return self.finish(Err(usb::error::ErrorKind::Timeout.into()));
+                            }
+
+                            self.state = CommandState::Backoff(until);
+                            self.attempt += 1;
+                            self.backoff = cmp::min(self.backoff * 2, self.device.retry_policy.max_backoff);
+                        },
+                        Err(e) => return self.fail(e),
+                    }
+                },
+                CommandState::Done => panic!("CommandFuture polled after completion"),
+            }
+        }
+    }
+}
+
+impl <T> Drop for CommandFuture<T> where T: HidTransport {
+    fn drop(&mut self) {
+        if let CommandState::Pending(_) = self.state {
+            // nobody is left to hear about a failure
+            let _ = self.device.cancel();
+        }
+
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use ::Verify;
+
+    fn device(poller: &Poller) -> (Arc<SoftHidTransport>, AsyncDevice<Arc<SoftHidTransport>>) {
+        let transport = Arc::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+
+        let mut device = U2fHidDevice::new(transport.clone());
+        device.init().unwrap();
+
+        (transport, AsyncDevice::new(device, poller))
+    }
+
+    #[test]
+    fn test_register_resolves_on_touch() {
+        let poller = Poller::new();
+        let (transport, device) = device(&poller);
+        transport.authenticator().set_auto_presence(false);
+
+        let touched = transport.clone();
+        let touch = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            touched.authenticator().touch();
+        });
+
+        let challenge = vec![1; 32];
+        let application = vec![2; 32];
+
+        let response = device.register(&challenge, &application).wait().unwrap();
+        touch.join().unwrap();
+
+        response.verify(&challenge, &application).unwrap();
+    }
+
+    #[test]
+    fn test_commands_on_several_devices() {
+        let poller = Poller::new();
+        let (_, first) = device(&poller);
+        let (_, second) = device(&poller);
+
+        let all = first.get_version()
+            .join(second.get_version())
+            .join(first.ping().join(second.wink()));
+
+        let ((first_version, second_version), _) = all.wait().unwrap();
+
+        assert_eq!((first_version, second_version), (U2fVersion::V2, U2fVersion::V2));
+    }
+
+    #[test]
+    fn test_silent_device_times_out() {
+        let poller = Poller::new();
+        let (host, _token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts.report = Duration::from_millis(200);
+
+        let device = AsyncDevice::new(device, &poller);
+
+        match device.ping().wait() {
+            Err(Error(ErrorKind::HidError(usb::error::ErrorKind::Timeout), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_ping_checks_echo() {
+        let poller = Poller::new();
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Ping, &mut ByteBuffer::from_bytes(&[1])).unwrap();
+        });
+
+        match AsyncDevice::new(device, &poller).ping().wait() {
+            Err(Error(ErrorKind::HidError(usb::error::ErrorKind::PingMismatch(0)), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_backoff_bounded_by_deadline() {
+        let poller = Poller::new();
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts = Timeouts::with_deadline(Duration::from_millis(300));
+        device.retry_policy.initial_backoff = Duration::from_millis(1000);
+
+        let busy = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Error, &mut ByteBuffer::from_bytes(&[U2fHidErrorCode::ChannelBusy as u8])).unwrap();
+        });
+
+        let started = Instant::now();
+
+        match AsyncDevice::new(device, &poller).ping().wait() {
+            Err(Error(ErrorKind::HidError(usb::error::ErrorKind::Timeout), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // gave up rather than sleeping past the deadline
+        assert!(started.elapsed() < Duration::from_millis(1000));
+
+        busy.join().unwrap();
+    }
+
+    #[test]
+    fn test_recovers_channel_after_error() {
+        let poller = Poller::new();
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let confused = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            // answers a ping with a wink
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Wink, &mut ByteBuffer::new()).unwrap();
+
+            // and moves us elsewhere when the channel is synchronised
+            buf.clear();
+            token.recv_response(U2fHidCommand::Init, &mut buf).unwrap();
+            let mut response = ByteBuffer::from_bytes(&buf.to_bytes()[0..8]);
+            response.write_u32(0x05060708);
+            response.write_bytes(&[2, 0, 0, 0, 0]);
+            token.send_request(U2fHidCommand::Init, &mut response).unwrap();
+        });
+
+        match AsyncDevice::new(device, &poller).ping().wait() {
+            Err(Error(ErrorKind::HidError(usb::error::ErrorKind::ChannelReassigned(0x05060708)), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        confused.join().unwrap();
+    }
+}
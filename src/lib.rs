@@ -1,3 +1,5 @@
+#![recursion_limit = "1024"]
+
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
@@ -8,8 +10,11 @@ extern crate error_chain;
 extern crate bytebuffer;
 #[macro_use]
 extern crate enum_primitive;
+#[cfg(feature = "hidapi")]
 extern crate hidapi;
+extern crate libc;
 extern crate rand;
+extern crate ring;
 extern crate webpki;
 extern crate untrusted;
 extern crate owning_ref;
@@ -22,10 +27,20 @@ pub mod api;
 pub mod raw;
 pub mod usb;
 pub mod error;
+#[cfg(any(test, feature = "soft"))]
+pub mod soft;
+pub mod manager;
+pub mod dissect;
+pub mod future;
+pub mod worker;
+pub mod describe;
 
 use std::cell::RefCell;
+use std::thread;
+use std::time::{Duration, Instant};
 use bytebuffer::*;
 use usb::hid::*;
+use usb::transport::HidTransport;
 use raw::frame::*;
 use error::*;
 use owning_ref::*;
@@ -37,8 +52,30 @@ pub const TEST_USER_PRESENCE_TEST_ONLY: u8 = 4;
 
 pub const AUTH_USER_PRESENCE_ENFORCE: u8 = TEST_USER_PRESENCE_REQUIRED | TEST_USER_PRESENCE_CONSUME;
 pub const AUTH_USER_PRESENCE_CHECK: u8 = TEST_USER_PRESENCE_REQUIRED | TEST_USER_PRESENCE_CONSUME | TEST_USER_PRESENCE_TEST_ONLY;
+pub const AUTH_DONT_ENFORCE_USER_PRESENCE: u8 = 8;
+
+pub const USER_PRESENCE_POLL_MILLIS: u64 = 200;
+
+/// Repeats `f` while it fails with `UserPresenceRequired`, i.e. until the user
+/// touches the device, for at most `timeout`.
+pub fn with_user_presence<F, R>(timeout: Duration, mut f: F) -> Result<R> where F: FnMut() -> Result<R> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match f() {
+            Err(Error(ErrorKind::UserPresenceRequired, _)) => {
+                if Instant::now() >= deadline {
+                    bail!(ErrorKind::Timeout);
+                }
+            },
+            result => return result,
+        }
+
+        thread::sleep(Duration::from_millis(USER_PRESENCE_POLL_MILLIS));
+    }
+}
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum U2fVersion {
     V2
 }
@@ -111,108 +148,192 @@ pub trait U2fDevice {
     fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<AuthenticateResponse>;
 
     fn get_version(&self) -> Result<U2fVersion>;
+
+    /// Asks the device whether `key_handle` is one of its own for
+    /// `application_param`, without waiting for (or blinking for) a touch.
+    ///
+    /// Devices that cannot tell claim every key handle, so they are still
+    /// asked to sign.
+    fn accepts_key_handle(&self, _application_param: &[u8], _key_handle: &[u8]) -> Result<bool> {
+        Ok(true)
+    }
 }
 
-impl <'a> U2fDevice for U2fHidDevice<'a> {
+impl <T> U2fDevice for U2fHidDevice<T> where T: HidTransport {
     fn register<'b>(&self, challenge_param: &[u8], application_param: &[u8]) -> Result<RegisterResponse> {
-        if challenge_param.len() != 32 {
-            bail!(ErrorKind::InvalidChallengeParameter);
-        }
+        send_register(self, challenge_param, application_param)
+    }
 
-        if application_param.len() != 32 {
-            bail!(ErrorKind::InvalidApplicationParameter);
-        }
+    fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<AuthenticateResponse> {
+        send_authenticate(self, challenge_param, application_param, key_handle)
+    }
 
-        let mut buf = ByteBuffer::new();
+    fn get_version(&self) -> Result<U2fVersion> {
+        send_get_version(self)
+    }
 
-        buf.write_bytes(challenge_param);
-        buf.write_bytes(application_param);
+    fn accepts_key_handle(&self, application_param: &[u8], key_handle: &[u8]) -> Result<bool> {
+        send_check_only(self, application_param, key_handle)
+    }
+}
 
-        let response = match self.send_apdu::<ExtendedEncoderV1>(
-                CommandAPDU::new(U2fCommand::Register, AUTH_USER_PRESENCE_ENFORCE, 0, buf.to_bytes(), Some(256))) {
-            Ok(response) => response,
-            Err(usb::error::Error(usb::error::ErrorKind::ErrorStatus(U2fStatusWord::ConditionsNotSatisfied), _)) => bail!(ErrorKind::UserPresenceRequired),
-            Err(e) => bail!(e)
-        };
+#[cfg(any(test, feature = "soft"))]
+impl U2fDevice for soft::SoftAuthenticator {
+    fn register<'b>(&self, challenge_param: &[u8], application_param: &[u8]) -> Result<RegisterResponse> {
+        send_register(self, challenge_param, application_param)
+    }
 
-        buf = ByteBuffer::from_bytes(&response.response_data[..]);
+    fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<AuthenticateResponse> {
+        send_authenticate(self, challenge_param, application_param, key_handle)
+    }
 
-        let reserved = buf.read_u8();
-        if reserved != 0x05 {
-            bail!(ErrorKind::InvalidRegistrationResponse);
-        }
-        let public_key = buf.read_bytes(65);
-        let key_handle_len = buf.read_u8() as usize;
-        if(buf.len() - buf.get_rpos()) <= key_handle_len {
-            bail!(ErrorKind::InvalidRegistrationResponse);
-        }
-        let key_handle = buf.read_bytes(key_handle_len);
+    fn get_version(&self) -> Result<U2fVersion> {
+        send_get_version(self)
+    }
+
+    fn accepts_key_handle(&self, application_param: &[u8], key_handle: &[u8]) -> Result<bool> {
+        send_check_only(self, application_param, key_handle)
+    }
+}
 
-        let remaining_len = buf.len() - buf.get_rpos();
-        let remaining = buf.read_bytes(remaining_len);
+fn send_register<C: SmartCard>(card: &C, challenge_param: &[u8], application_param: &[u8]) -> Result<RegisterResponse> {
+    let response = card.send_apdu::<ExtendedEncoderV1>(register_apdu(challenge_param, application_param)?)
+        .map_err(apdu_error)?;
 
-        let cert_len = cert_len(&remaining[..]);
-        let cert_bytes = remaining[0..cert_len].to_owned();
-        let signature = remaining[cert_len..].to_owned();
-        
-        Ok(RegisterResponse {
-            user_public_key: public_key,
-            key_handle: key_handle,
-            attestation_cert: cert_bytes,
-            signature: signature
-        })
+    parse_register_response(&response.response_data)
+}
+
+fn send_authenticate<C: SmartCard>(card: &C, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<AuthenticateResponse> {
+    let request = authenticate_apdu(AUTH_USER_PRESENCE_ENFORCE, challenge_param, application_param, key_handle)?;
+    let response = card.send_apdu::<ExtendedEncoderV1>(request).map_err(apdu_error)?;
+
+    parse_authenticate_response(&response.response_data)
+}
+
+fn send_check_only<C: SmartCard>(card: &C, application_param: &[u8], key_handle: &[u8]) -> Result<bool> {
+    // challenge, unused by check-only
+    let request = authenticate_apdu(AUTH_USER_PRESENCE_CHECK, &[0; 32], application_param, key_handle)?;
+
+    // check-only never succeeds: the status tells whether the key handle
+    // would have been accepted
+    match card.send_apdu::<ExtendedEncoderV1>(request) {
+        Err(usb::error::Error(usb::error::ErrorKind::ErrorStatus(U2fStatusWord::ConditionsNotSatisfied), _)) => Ok(true),
+        Err(usb::error::Error(usb::error::ErrorKind::ErrorStatus(U2fStatusWord::WrongData), _)) => Ok(false),
+        Err(e) => bail!(e),
+        Ok(_) => Ok(true),
     }
+}
 
-    fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<AuthenticateResponse> {
-        if challenge_param.len() != 32 {
-            bail!(ErrorKind::InvalidChallengeParameter);
-        }
+fn send_get_version<C: SmartCard>(card: &C) -> Result<U2fVersion> {
+    let response = card.send_apdu::<ExtendedEncoderV1>(version_apdu())?;
 
-        if application_param.len() != 32 {
-            bail!(ErrorKind::InvalidApplicationParameter);
-        }
+    parse_version(&response.response_data)
+}
 
-        if key_handle.len() >= 256 {
-            bail!(ErrorKind::KeyHandleTooLong);
-        }
+fn register_apdu(challenge_param: &[u8], application_param: &[u8]) -> Result<CommandAPDU> {
+    if challenge_param.len() != 32 {
+        bail!(ErrorKind::InvalidChallengeParameter);
+    }
 
-        let mut buf = ByteBuffer::new();
+    if application_param.len() != 32 {
+        bail!(ErrorKind::InvalidApplicationParameter);
+    }
 
-        buf.write_bytes(challenge_param);
-        buf.write_bytes(application_param);
-        buf.write_u8(key_handle.len() as u8);
-        buf.write_bytes(key_handle);
+    let mut buf = ByteBuffer::new();
 
-        let response = match self.send_apdu::<ExtendedEncoderV1>(
-                CommandAPDU::new(U2fCommand::Authenticate, AUTH_USER_PRESENCE_ENFORCE, 0, buf.to_bytes(), Some(256))) {
-            Ok(response) => response,
-            Err(usb::error::Error(usb::error::ErrorKind::ErrorStatus(U2fStatusWord::ConditionsNotSatisfied), _)) => bail!(ErrorKind::UserPresenceRequired),
-            Err(e) => bail!(e)
-        };
+    buf.write_bytes(challenge_param);
+    buf.write_bytes(application_param);
 
-        buf = ByteBuffer::from_bytes(response.response_data.as_slice());
-        
-        buf.read_u8(); // user presence
-        let counter = buf.read_u32();
-        let signature_len = buf.len() - buf.get_rpos();
-        let signature = buf.read_bytes(signature_len);
+    Ok(CommandAPDU::new(U2fCommand::Register, AUTH_USER_PRESENCE_ENFORCE, 0, buf.to_bytes(), Some(256)))
+}
+
+fn parse_register_response(response_data: &[u8]) -> Result<RegisterResponse> {
+    let mut buf = ByteBuffer::from_bytes(response_data);
 
-        Ok(AuthenticateResponse {
-            counter: counter,
-            signature: signature
-        })
+    let reserved = buf.read_u8();
+    if reserved != 0x05 {
+        bail!(ErrorKind::InvalidRegistrationResponse);
     }
 
-    fn get_version(&self) -> Result<U2fVersion> {
-        println!("sending version command");
+    let public_key = buf.read_bytes(65);
+    let key_handle_len = buf.read_u8() as usize;
+    if(buf.len() - buf.get_rpos()) <= key_handle_len {
+        bail!(ErrorKind::InvalidRegistrationResponse);
+    }
+    let key_handle = buf.read_bytes(key_handle_len);
 
-        let response = self.send_apdu::<ExtendedEncoderV1>(CommandAPDU::new(U2fCommand::Version, 0, 0, vec![], Some(256)))?;
+    let remaining_len = buf.len() - buf.get_rpos();
+    let remaining = buf.read_bytes(remaining_len);
 
-        if response.response_data == "U2F_V2".as_bytes() {
-            Ok(U2fVersion::V2)
-        } else {
-            Err(ErrorKind::UnrecognisedVersion.into())
-        }
+    let cert_len = cert_len(&remaining[..]);
+    let cert_bytes = remaining[0..cert_len].to_owned();
+    let signature = remaining[cert_len..].to_owned();
+
+    Ok(RegisterResponse {
+        user_public_key: public_key,
+        key_handle: key_handle,
+        attestation_cert: cert_bytes,
+        signature: signature
+    })
+}
+
+fn authenticate_apdu(control: u8, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<CommandAPDU> {
+    if challenge_param.len() != 32 {
+        bail!(ErrorKind::InvalidChallengeParameter);
+    }
+
+    if application_param.len() != 32 {
+        bail!(ErrorKind::InvalidApplicationParameter);
+    }
+
+    if key_handle.len() >= 256 {
+        bail!(ErrorKind::KeyHandleTooLong);
+    }
+
+    let mut buf = ByteBuffer::new();
+
+    buf.write_bytes(challenge_param);
+    buf.write_bytes(application_param);
+    buf.write_u8(key_handle.len() as u8);
+    buf.write_bytes(key_handle);
+
+    Ok(CommandAPDU::new(U2fCommand::Authenticate, control, 0, buf.to_bytes(), Some(256)))
+}
+
+fn parse_authenticate_response(response_data: &[u8]) -> Result<AuthenticateResponse> {
+    let mut buf = ByteBuffer::from_bytes(response_data);
+
+    buf.read_u8(); // user presence
+    let counter = buf.read_u32();
+    let signature_len = buf.len() - buf.get_rpos();
+    let signature = buf.read_bytes(signature_len);
+
+    Ok(AuthenticateResponse {
+        counter: counter,
+        signature: signature
+    })
+}
+
+fn version_apdu() -> CommandAPDU {
+    CommandAPDU::new(U2fCommand::Version, 0, 0, vec![], Some(256))
+}
+
+fn parse_version(response_data: &[u8]) -> Result<U2fVersion> {
+    if response_data == "U2F_V2".as_bytes() {
+        Ok(U2fVersion::V2)
+    } else {
+        Err(ErrorKind::UnrecognisedVersion.into())
+    }
+}
+
+/// Maps the errors of a register or authenticate APDU, in particular the
+/// device asking for a touch.
+fn apdu_error(e: usb::error::Error) -> Error {
+    match e {
+        usb::error::Error(usb::error::ErrorKind::ErrorStatus(U2fStatusWord::ConditionsNotSatisfied), _) => ErrorKind::UserPresenceRequired.into(),
+        usb::error::Error(usb::error::ErrorKind::Cancelled, _) => ErrorKind::Cancelled.into(),
+        usb::error::Error(usb::error::ErrorKind::Timeout, _) => ErrorKind::Timeout.into(),
+        e => e.into(),
     }
 }
 
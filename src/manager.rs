@@ -0,0 +1,289 @@
+//! Runs U2F operations against every attached token at once.
+//!
+//! Each device gets its own thread that retries until the user touches it;
+//! the first device touched wins and the others are cancelled.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::thread::JoinHandle;
+
+use usb::hid::*;
+use usb::transport::*;
+use error::*;
+use ::{with_user_presence, U2fDevice, RegisterResponse, AuthenticateResponse};
+
+enum Slot<T> {
+    Idle(U2fHidDevice<T>),
+    Running(JoinHandle<U2fHidDevice<T>>),
+}
+
+pub struct DeviceManager<T> {
+    devices: Vec<U2fHidDevice<T>>,
+}
+
+impl <T> DeviceManager<T> where T: HidTransport + Send + 'static {
+    /// Takes over `devices`, which must already be initialised.
+    pub fn new(devices: Vec<U2fHidDevice<T>>) -> DeviceManager<T> {
+        DeviceManager {
+            devices: devices,
+        }
+    }
+
+    pub fn devices(&self) -> &[U2fHidDevice<T>] {
+        &self.devices
+    }
+
+    pub fn into_devices(self) -> Vec<U2fHidDevice<T>> {
+        self.devices
+    }
+
+    /// Registers with whichever device is touched first. Returns its index
+    /// in `devices()` with the response.
+    pub fn register(&mut self, challenge_param: &[u8], application_param: &[u8]) -> Result<(usize, RegisterResponse)> {
+        let challenge_param = challenge_param.to_owned();
+        let application_param = application_param.to_owned();
+
+        let all = (0..self.devices.len()).collect();
+
+        self.race(all, move |device| device.register(&challenge_param, &application_param))
+    }
+
+    /// Authenticates with whichever device is touched first, out of those
+    /// that recognise `key_handle`. The others are never asked to sign, so
+    /// they don't blink.
+    pub fn authenticate(&mut self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Result<(usize, AuthenticateResponse)> {
+        let mut candidates = vec![];
+
+        for (index, device) in self.devices.iter().enumerate() {
+            match device.accepts_key_handle(application_param, key_handle) {
+                Ok(true) => candidates.push(index),
+                Ok(false) => {},
+                // a device that cannot say is not asked to sign
+                Err(_) => {},
+            }
+        }
+
+        let challenge_param = challenge_param.to_owned();
+        let application_param = application_param.to_owned();
+        let key_handle = key_handle.to_owned();
+
+        self.race(candidates, move |device| device.authenticate(&challenge_param, &application_param, &key_handle))
+    }
+
+    /// Runs `op` on the devices at `indices`, each on its own thread, until
+    /// one succeeds. The rest are cancelled and all devices are handed back
+    /// to the manager before returning.
+    fn race<R, F>(&mut self, indices: Vec<usize>, op: F) -> Result<(usize, R)>
+        where R: Send + 'static, F: Fn(&U2fHidDevice<T>) -> Result<R> + Send + Sync + 'static
+    {
+        if indices.is_empty() {
+            bail!(ErrorKind::NoDevice);
+        }
+
+        let op = Arc::new(op);
+        let (sender, receiver) = channel();
+
+        let mut cancel_handles = vec![];
+        let mut slots = vec![];
+        // cancels only reach commands in flight, so the losers also check
+        // this between attempts
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        for (index, device) in self.devices.drain(..).enumerate() {
+            if !indices.contains(&index) {
+                slots.push(Slot::Idle(device));
+                cancel_handles.push(None);
+                continue;
+            }
+
+            cancel_handles.push(Some(device.cancel_handle()));
+
+            let sender = sender.clone();
+            let op = op.clone();
+            let stopped = stopped.clone();
+
+            slots.push(Slot::Running(thread::spawn(move || {
+                let result = with_user_presence(device.timeouts.user_presence, || {
+                    if stopped.load(Ordering::SeqCst) {
+                        bail!(ErrorKind::Cancelled);
+                    }
+
+                    op(&device)
+                });
+                let _ = sender.send((index, result));
+                device
+            })));
+        }
+
+        drop(sender);
+
+        let mut winner = None;
+        let mut first_error = None;
+
+        // ends once every thread has reported
+        for (index, result) in receiver.iter() {
+            match result {
+                Ok(response) => {
+                    if winner.is_none() {
+                        winner = Some((index, response));
+                        stopped.store(true, Ordering::SeqCst);
+
+                        for handle in cancel_handles.iter() {
+                            if let Some(ref handle) = *handle {
+                                handle.cancel();
+                            }
+                        }
+                    }
+                },
+                Err(e) => {
+                    if winner.is_none() {
+                        first_error = first_error.or(Some(e));
+                    }
+                },
+            }
+        }
+
+        for slot in slots.into_iter() {
+            self.devices.push(match slot {
+                Slot::Idle(device) => device,
+                Slot::Running(thread) => thread.join().expect("device thread"),
+            });
+        }
+
+        match (winner, first_error) {
+            (Some(winner), _) => Ok(winner),
+            (None, Some(e)) => Err(e),
+            (None, None) => bail!(ErrorKind::NoDevice),
+        }
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl DeviceManager<::usb::transport::OwnedHidDevice> {
+    /// Opens and initialises every FIDO device hidapi knows of, skipping
+    /// those that fail to open.
+    pub fn open_hidapi(context: &::usb::transport::HidContext) -> DeviceManager<::usb::transport::OwnedHidDevice> {
+        use usb::FidoExt;
+
+        let mut devices = vec![];
+
+        for info in context.fido_devices() {
+            let device = context.open_u2f(&info).and_then(|mut device| {
+                device.init()?;
+                Ok(device)
+            });
+
+            match device {
+                Ok(device) => devices.push(device),
+                Err(_) => {},
+            }
+        }
+
+        Self::new(devices)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceManager<::usb::hidraw::HidrawDevice> {
+    /// Opens and initialises every FIDO device found through hidraw,
+    /// skipping those that fail to open.
+    pub fn open_hidraw(hidraw: &::usb::hidraw::Hidraw) -> DeviceManager<::usb::hidraw::HidrawDevice> {
+        use usb::FidoExt;
+
+        let mut devices = vec![];
+
+        for info in hidraw.fido_devices() {
+            let device = hidraw.open_u2f(&info).and_then(|mut device| {
+                device.init()?;
+                Ok(device)
+            });
+
+            match device {
+                Ok(device) => devices.push(device),
+                Err(_) => {},
+            }
+        }
+
+        Self::new(devices)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use std::time::Duration;
+    use ::Verify;
+
+    fn devices(count: usize) -> (Vec<Arc<SoftHidTransport>>, DeviceManager<Arc<SoftHidTransport>>) {
+        let transports = (0..count)
+            .map(|i| Arc::new(SoftHidTransport::new(SoftAuthenticator::new(&[i as u8; 32]))))
+            .collect::<Vec<Arc<SoftHidTransport>>>();
+
+        let devices = transports.iter().map(|transport| {
+            transport.authenticator().set_auto_presence(false);
+
+            let mut device = U2fHidDevice::new(transport.clone());
+            device.init().unwrap();
+            device
+        }).collect();
+
+        (transports, DeviceManager::new(devices))
+    }
+
+    #[test]
+    fn test_register_with_touched_device() {
+        let (transports, mut manager) = devices(3);
+
+        let touched = transports[1].clone();
+        let touch = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            touched.authenticator().touch();
+        });
+
+        let challenge = vec![1; 32];
+        let application = vec![2; 32];
+
+        let (index, response) = manager.register(&challenge, &application).unwrap();
+        touch.join().unwrap();
+
+        assert_eq!(index, 1);
+        response.verify(&challenge, &application).unwrap();
+        assert_eq!(manager.devices().len(), 3);
+
+        // the cancelled devices are still usable
+        manager.devices()[0].ping().unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_only_matching_device() {
+        let (transports, mut manager) = devices(2);
+
+        let challenge = vec![1; 32];
+        let application = vec![2; 32];
+
+        transports[1].authenticator().touch();
+        let key_handle = manager.devices()[1].register(&challenge, &application).unwrap().key_handle;
+
+        // a touch on the other device must not count
+        transports[0].authenticator().touch();
+        transports[1].authenticator().touch();
+
+        let (index, _) = manager.authenticate(&challenge, &application, &key_handle).unwrap();
+
+        assert_eq!(index, 1);
+        assert_eq!(transports[0].authenticator().counter(), 0);
+    }
+
+    #[test]
+    fn test_authenticate_without_matching_device() {
+        let (_, mut manager) = devices(2);
+
+        match manager.authenticate(&[1; 32], &[2; 32], &[3; 64]) {
+            Err(Error(ErrorKind::NoDevice, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -12,6 +12,7 @@ enum_from_primitive! {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum U2fStatusWord {
     NoError = 0x9000,
+    WrongLength = 0x6700,
     WrongData = 0x6984,
     ConditionsNotSatisfied = 0x6985,
     InsNotSupported = 0x6d00,
@@ -255,7 +256,7 @@ mod test {
     fn test_extended_get_version() {
         let expected = vec![0, 3, 0, 0, 0, 0, 0];
         let mut bb = ByteBuffer::new();
-        ExtendedEncoder::encode(&mut bb, CommandAPDU::new(U2fCommand::Version, 0, 0, vec![], Some(65536))).unwrap();
+        ExtendedEncoderV1_1::encode(&mut bb, CommandAPDU::new(U2fCommand::Version, 0, 0, vec![], Some(65536))).unwrap();
         assert_eq!(bb.to_bytes(), expected);
     }
 
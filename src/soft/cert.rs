@@ -0,0 +1,72 @@
+//! Self-signed X.509 attestation certificates for the software authenticator.
+
+use ring::digest;
+use rand::Rng;
+
+use super::der::*;
+use super::p256::PrivateKey;
+
+const OID_ECDSA_WITH_SHA256: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &'static [u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &'static [u8] = &[0x55, 0x04, 0x03];
+const OID_FIDO_U2F_TRANSPORTS: &'static [u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xe5, 0x1c, 0x02, 0x01, 0x01];
+
+const TRANSPORTS_USB: &'static [u8] = &[0x05, 0x20]; // bit 2 of a 3 bit string
+
+const NOT_BEFORE: &'static str = "170101000000Z";
+const NOT_AFTER: &'static str = "491231235959Z";
+
+fn name(common_name: &str) -> Vec<u8> {
+    let attribute = sequence(&[tlv(TAG_OID, OID_COMMON_NAME), tlv(TAG_UTF8_STRING, common_name.as_bytes())]);
+    sequence(&[tlv(TAG_SET, &attribute)])
+}
+
+/// Builds a DER encoded v3 certificate for `key`, signed by itself.
+pub fn self_signed<R: Rng>(key: &PrivateKey, common_name: &str, serial: u32, rng: &mut R) -> Vec<u8> {
+    let signature_algorithm = sequence(&[tlv(TAG_OID, OID_ECDSA_WITH_SHA256)]);
+
+    let serial = [(serial >> 24) as u8, (serial >> 16) as u8, (serial >> 8) as u8, serial as u8];
+
+    let tbs = sequence(&[
+        tlv(0xa0, &integer(&[2])), // [0] version v3
+        integer(&serial),
+        signature_algorithm.clone(),
+        name(common_name),
+        sequence(&[tlv(TAG_UTC_TIME, NOT_BEFORE.as_bytes()), tlv(TAG_UTC_TIME, NOT_AFTER.as_bytes())]),
+        name(common_name),
+        sequence(&[
+            sequence(&[tlv(TAG_OID, OID_EC_PUBLIC_KEY), tlv(TAG_OID, OID_PRIME256V1)]),
+            bit_string(key.public_key()),
+        ]),
+        // webpki insists on an extensions block
+        tlv(0xa3, &sequence(&[
+            sequence(&[tlv(TAG_OID, OID_FIDO_U2F_TRANSPORTS), tlv(TAG_OCTET_STRING, &tlv(TAG_BIT_STRING, TRANSPORTS_USB))]),
+        ])),
+    ]);
+
+    let signature = key.sign_digest(digest::digest(&digest::SHA256, &tbs).as_ref(), rng);
+
+    sequence(&[tbs, signature_algorithm, bit_string(&signature)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand;
+    use untrusted;
+    use webpki;
+
+    #[test]
+    fn test_signature_verifies_against_certificate() {
+        let key = PrivateKey::from_scalar(&[7; 32]).unwrap();
+        let cert = self_signed(&key, "test", 1, &mut rand::thread_rng());
+
+        let message = b"signed by the attestation key";
+        let signature = key.sign_digest(digest::digest(&digest::SHA256, message).as_ref(), &mut rand::thread_rng());
+
+        let cert = ::parse_cert(&cert).unwrap();
+        cert.verify_signature(&webpki::ECDSA_P256_SHA256, untrusted::Input::from(message), untrusted::Input::from(&signature)).unwrap();
+        assert!(cert.verify_signature(&webpki::ECDSA_P256_SHA256, untrusted::Input::from(b"something else"), untrusted::Input::from(&signature)).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Just enough DER encoding to build certificates and ECDSA signatures.
+
+pub const TAG_INTEGER: u8 = 0x02;
+pub const TAG_BIT_STRING: u8 = 0x03;
+pub const TAG_OCTET_STRING: u8 = 0x04;
+pub const TAG_OID: u8 = 0x06;
+pub const TAG_UTF8_STRING: u8 = 0x0c;
+pub const TAG_UTC_TIME: u8 = 0x17;
+pub const TAG_SEQUENCE: u8 = 0x30;
+pub const TAG_SET: u8 = 0x31;
+
+pub fn tlv(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut r = vec![tag];
+
+    let len = value.len();
+    if len < 0x80 {
+        r.push(len as u8);
+    } else if len < 0x100 {
+        r.push(0x81);
+        r.push(len as u8);
+    } else {
+        r.push(0x82);
+        r.push((len >> 8) as u8);
+        r.push(len as u8);
+    }
+
+    r.extend_from_slice(value);
+    r
+}
+
+pub fn sequence(items: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = vec![];
+    for item in items {
+        body.extend_from_slice(item);
+    }
+    tlv(TAG_SEQUENCE, &body)
+}
+
+/// Minimal encoding of a non-negative big-endian integer.
+pub fn integer(bytes: &[u8]) -> Vec<u8> {
+    let mut start = 0;
+    while start < bytes.len() - 1 && bytes[start] == 0 {
+        start += 1;
+    }
+
+    let mut value = vec![];
+    if bytes[start] & 0x80 != 0 {
+        value.push(0);
+    }
+    value.extend_from_slice(&bytes[start..]);
+
+    tlv(TAG_INTEGER, &value)
+}
+
+pub fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    let mut value = vec![0]; // no unused bits
+    value.extend_from_slice(bytes);
+    tlv(TAG_BIT_STRING, &value)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_integer_encoding() {
+        assert_eq!(integer(&[0, 0, 0x7f]), vec![TAG_INTEGER, 1, 0x7f]);
+        assert_eq!(integer(&[0, 0x80, 1]), vec![TAG_INTEGER, 3, 0, 0x80, 1]);
+        assert_eq!(integer(&[0xff; 32])[0..4], [TAG_INTEGER, 33, 0, 0xff]);
+        assert_eq!(integer(&[0; 32]), vec![TAG_INTEGER, 1, 0]);
+    }
+
+    #[test]
+    fn test_long_lengths() {
+        assert_eq!(tlv(TAG_OCTET_STRING, &[0; 0x7f])[0..2], [TAG_OCTET_STRING, 0x7f]);
+        assert_eq!(tlv(TAG_OCTET_STRING, &[0; 0x80])[0..3], [TAG_OCTET_STRING, 0x81, 0x80]);
+        assert_eq!(tlv(TAG_OCTET_STRING, &[0; 0x12c])[0..4], [TAG_OCTET_STRING, 0x82, 0x01, 0x2c]);
+    }
+}
@@ -0,0 +1,708 @@
+//! Software U2F authenticator for running the protocol stack without a token.
+//!
+//! `SoftAuthenticator` answers U2F APDUs directly and so implements
+//! `U2fDevice` on its own. Wrapping it in a `SoftHidTransport` puts it behind
+//! the U2FHID framing layer, where it can be driven through `U2fHidDevice`
+//! exactly like a physical key.
+//!
+//! Key handles are derived from a master secret, so two authenticators built
+//! from the same secret recognise each other's registrations.
+
+pub mod der;
+pub mod p256;
+pub mod cert;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use bytebuffer::*;
+use rand;
+use rand::Rng;
+use ring::{constant_time, digest, hmac};
+use enum_primitive::FromPrimitive;
+
+use usb::error::*;
+use usb::hid::*;
+use usb::transport::*;
+use raw::frame::*;
+use self::p256::PrivateKey;
+use ::{AUTH_USER_PRESENCE_CHECK, AUTH_USER_PRESENCE_ENFORCE, AUTH_DONT_ENFORCE_USER_PRESENCE};
+
+pub const SOFT_ATTESTATION_NAME: &'static str = "u2f-rs soft authenticator";
+
+const KEY_HANDLE_NONCE_LEN: usize = 32;
+const KEY_HANDLE_LEN: usize = 64;
+
+struct SoftState {
+    counter: u32,
+    auto_presence: bool,
+    user_present: bool,
+}
+
+pub struct SoftAuthenticator {
+    key_handle_key: hmac::SigningKey,
+    private_key_key: hmac::SigningKey,
+    attestation_key: PrivateKey,
+    attestation_cert: Vec<u8>,
+    state: Mutex<SoftState>,
+}
+
+type ApduResult = ::std::result::Result<Vec<u8>, U2fStatusWord>;
+
+impl SoftAuthenticator {
+    /// Creates an authenticator whose keys are all derived from `master_key`.
+    pub fn new(master_key: &[u8]) -> SoftAuthenticator {
+        let master = hmac::SigningKey::new(&digest::SHA256, master_key);
+
+        let key_handle_key = hmac::SigningKey::new(&digest::SHA256,
+            hmac::sign(&master, b"key handle").as_ref());
+        let private_key_key = hmac::SigningKey::new(&digest::SHA256,
+            hmac::sign(&master, b"private key").as_ref());
+
+        let attestation_key = derive_key(&master, b"attestation");
+        let attestation_cert = cert::self_signed(&attestation_key, SOFT_ATTESTATION_NAME, 1, &mut rand::thread_rng());
+
+        SoftAuthenticator {
+            key_handle_key: key_handle_key,
+            private_key_key: private_key_key,
+            attestation_key: attestation_key,
+            attestation_cert: attestation_cert,
+            state: Mutex::new(SoftState {
+                counter: 0,
+                auto_presence: true,
+                user_present: false,
+            }),
+        }
+    }
+
+    /// Creates an authenticator with a random master secret.
+    pub fn generate() -> SoftAuthenticator {
+        let mut master_key = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut master_key);
+
+        Self::new(&master_key)
+    }
+
+    pub fn attestation_cert(&self) -> &[u8] {
+        &self.attestation_cert
+    }
+
+    pub fn counter(&self) -> u32 {
+        self.state.lock().expect("soft state lock").counter
+    }
+
+    pub fn set_counter(&self, counter: u32) {
+        self.state.lock().expect("soft state lock").counter = counter;
+    }
+
+    /// When enabled (the default) every request needing user presence is
+    /// approved. When disabled each approval needs a prior call to `touch`.
+    pub fn set_auto_presence(&self, auto_presence: bool) {
+        self.state.lock().expect("soft state lock").auto_presence = auto_presence;
+    }
+
+    /// Simulates the user touching the key. Consumed by the next request
+    /// that enforces user presence.
+    pub fn touch(&self) {
+        self.state.lock().expect("soft state lock").user_present = true;
+    }
+
+    fn consume_presence(&self) -> bool {
+        let mut state = self.state.lock().expect("soft state lock");
+
+        if state.auto_presence {
+            return true;
+        }
+
+        let present = state.user_present;
+        state.user_present = false;
+        present
+    }
+
+    /// Processes an encoded command APDU, returning response data followed
+    /// by the status word.
+    pub fn process_apdu(&self, apdu: &[u8]) -> Vec<u8> {
+        let (mut response, status) = match self.dispatch_apdu(apdu) {
+            Ok(data) => (data, U2fStatusWord::NoError),
+            Err(status) => (vec![], status),
+        };
+
+        let sw = status as u16;
+        response.push((sw >> 8) as u8);
+        response.push(sw as u8);
+
+        response
+    }
+
+    fn dispatch_apdu(&self, apdu: &[u8]) -> ApduResult {
+        if apdu.len() < 4 {
+            return Err(U2fStatusWord::WrongLength);
+        }
+
+        if apdu[0] != 0 {
+            return Err(U2fStatusWord::ClaNotSupported);
+        }
+
+        let ins = apdu[1];
+        let p1 = apdu[2];
+        let data = request_data(&apdu[4..])?;
+
+        match ins {
+            x if x == U2fCommand::Register as u8 => self.process_register(data),
+            x if x == U2fCommand::Authenticate as u8 => self.process_authenticate(p1, data),
+            x if x == U2fCommand::Version as u8 => Ok("U2F_V2".as_bytes().to_owned()),
+            _ => Err(U2fStatusWord::InsNotSupported),
+        }
+    }
+
+    fn process_register(&self, data: &[u8]) -> ApduResult {
+        if data.len() != 64 {
+            return Err(U2fStatusWord::WrongLength);
+        }
+
+        let challenge_param = &data[0..32];
+        let application_param = &data[32..64];
+
+        if !self.consume_presence() {
+            return Err(U2fStatusWord::ConditionsNotSatisfied);
+        }
+
+        let mut rng = rand::thread_rng();
+
+        let mut nonce = [0u8; KEY_HANDLE_NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let key = self.user_key(application_param, &nonce);
+        let key_handle = self.key_handle(application_param, &nonce);
+
+        let mut msg = ByteBuffer::new();
+        msg.write_u8(0);
+        msg.write_bytes(application_param);
+        msg.write_bytes(challenge_param);
+        msg.write_bytes(&key_handle);
+        msg.write_bytes(key.public_key());
+
+        let signature = self.attestation_key.sign_digest(
+            digest::digest(&digest::SHA256, &msg.to_bytes()).as_ref(), &mut rng);
+
+        let mut response = ByteBuffer::new();
+        response.write_u8(0x05);
+        response.write_bytes(key.public_key());
+        response.write_u8(key_handle.len() as u8);
+        response.write_bytes(&key_handle);
+        response.write_bytes(&self.attestation_cert);
+        response.write_bytes(&signature);
+
+        Ok(response.to_bytes())
+    }
+
+    fn process_authenticate(&self, p1: u8, data: &[u8]) -> ApduResult {
+        if data.len() < 65 || data.len() != 65 + data[64] as usize {
+            return Err(U2fStatusWord::WrongLength);
+        }
+
+        let challenge_param = &data[0..32];
+        let application_param = &data[32..64];
+        let key_handle = &data[65..];
+
+        let nonce = match self.check_key_handle(application_param, key_handle) {
+            Some(nonce) => nonce,
+            None => return Err(U2fStatusWord::WrongData),
+        };
+
+        let user_presence = match p1 {
+            AUTH_USER_PRESENCE_CHECK => return Err(U2fStatusWord::ConditionsNotSatisfied),
+            AUTH_USER_PRESENCE_ENFORCE => {
+                if !self.consume_presence() {
+                    return Err(U2fStatusWord::ConditionsNotSatisfied);
+                }
+                1
+            },
+            AUTH_DONT_ENFORCE_USER_PRESENCE => 0,
+            _ => return Err(U2fStatusWord::WrongData),
+        };
+
+        let counter = {
+            let mut state = self.state.lock().expect("soft state lock");
+            state.counter = state.counter.wrapping_add(1);
+            state.counter
+        };
+
+        let key = self.user_key(application_param, nonce);
+
+        let mut msg = ByteBuffer::new();
+        msg.write_bytes(application_param);
+        msg.write_u8(user_presence);
+        msg.write_u32(counter);
+        msg.write_bytes(challenge_param);
+
+        let signature = key.sign_digest(
+            digest::digest(&digest::SHA256, &msg.to_bytes()).as_ref(), &mut rand::thread_rng());
+
+        let mut response = ByteBuffer::new();
+        response.write_u8(user_presence);
+        response.write_u32(counter);
+        response.write_bytes(&signature);
+
+        Ok(response.to_bytes())
+    }
+
+    fn user_key(&self, application_param: &[u8], nonce: &[u8]) -> PrivateKey {
+        let mut seed = application_param.to_owned();
+        seed.extend_from_slice(nonce);
+
+        derive_key(&self.private_key_key, &seed)
+    }
+
+    fn key_handle(&self, application_param: &[u8], nonce: &[u8]) -> Vec<u8> {
+        let mut data = application_param.to_owned();
+        data.extend_from_slice(nonce);
+
+        let mut key_handle = nonce.to_owned();
+        key_handle.extend_from_slice(hmac::sign(&self.key_handle_key, &data).as_ref());
+        key_handle
+    }
+
+    /// Returns the key handle's nonce if it was issued by us for this application.
+    fn check_key_handle<'a>(&self, application_param: &[u8], key_handle: &'a [u8]) -> Option<&'a [u8]> {
+        if key_handle.len() != KEY_HANDLE_LEN {
+            return None;
+        }
+
+        let nonce = &key_handle[0..KEY_HANDLE_NONCE_LEN];
+        let expected = self.key_handle(application_param, nonce);
+
+        match constant_time::verify_slices_are_equal(&expected, key_handle) {
+            Ok(()) => Some(nonce),
+            Err(_) => None,
+        }
+    }
+}
+
+impl SmartCard for SoftAuthenticator {
+    fn send_apdu<E>(&self, cmd: CommandAPDU) -> Result<ResponseAPDU> where E: RequestEncoder {
+        let mut bb = ByteBuffer::new();
+
+        E::encode(&mut bb, cmd)?;
+
+        let mut bb = ByteBuffer::from_bytes(&self.process_apdu(&bb.to_bytes()));
+
+        decode_apdu_response(&mut bb)
+    }
+}
+
+/// Derives a valid P-256 private key from an HMAC over `seed`.
+fn derive_key(key: &hmac::SigningKey, seed: &[u8]) -> PrivateKey {
+    let mut counter: u8 = 0;
+
+    loop {
+        let mut data = seed.to_owned();
+        data.push(counter);
+
+        if let Some(key) = PrivateKey::from_scalar(hmac::sign(key, &data).as_ref()) {
+            return key;
+        }
+
+        counter += 1;
+    }
+}
+
+/// Extracts the request data from the body of a short or extended length APDU.
+fn request_data(body: &[u8]) -> ::std::result::Result<&[u8], U2fStatusWord> {
+    if body.len() <= 1 {
+        // no data, maybe a short Le
+        return Ok(&body[0..0]);
+    }
+
+    let (offset, nc) = if body[0] == 0 {
+        if body.len() < 3 {
+            return Err(U2fStatusWord::WrongLength);
+        }
+        (3, ((body[1] as usize) << 8) | (body[2] as usize))
+    } else {
+        (1, body[0] as usize)
+    };
+
+    if body.len() < offset + nc {
+        return Err(U2fStatusWord::WrongLength);
+    }
+
+    Ok(&body[offset..offset + nc])
+}
+
+struct PendingMessage {
+    channel_id: u32,
+    command: u8,
+    len: usize,
+    data: Vec<u8>,
+    next_seq: u8,
+}
+
+struct SoftHidState {
+    packet_size: usize,
+    next_channel_id: u32,
+    pending: Option<PendingMessage>,
+    output: VecDeque<Vec<u8>>,
+    lock: Option<(u32, Instant)>,
+}
+
+/// `HidTransport` that implements the device side of U2FHID in process,
+/// dispatching U2F messages to a `SoftAuthenticator`.
+pub struct SoftHidTransport {
+    authenticator: SoftAuthenticator,
+    state: Mutex<SoftHidState>,
+    // signalled when reports are queued for the host
+    output_ready: Condvar,
+}
+
+pub const SOFT_HID_CAPABILITIES: u8 = CAPABILITY_WINK | CAPABILITY_LOCK;
+
+impl SoftHidTransport {
+    pub fn new(authenticator: SoftAuthenticator) -> SoftHidTransport {
+        Self::with_packet_size(authenticator, HID_REPORT_SIZE)
+    }
+
+    /// Emulates a device with `packet_size` byte reports instead of the
+    /// usual 64.
+    pub fn with_packet_size(authenticator: SoftAuthenticator, packet_size: usize) -> SoftHidTransport {
+        SoftHidTransport {
+            authenticator: authenticator,
+            state: Mutex::new(SoftHidState {
+                packet_size: packet_size,
+                next_channel_id: 1,
+                pending: None,
+                output: VecDeque::new(),
+                lock: None,
+            }),
+            output_ready: Condvar::new(),
+        }
+    }
+
+    pub fn authenticator(&self) -> &SoftAuthenticator {
+        &self.authenticator
+    }
+
+    fn dispatch(&self, state: &mut SoftHidState, message: PendingMessage) {
+        let command = U2fHidCommand::from_u8(message.command);
+
+        if let Some((channel_id, expires)) = state.lock {
+            if expires <= Instant::now() {
+                state.lock = None;
+            } else if channel_id != message.channel_id && command != Some(U2fHidCommand::Init) {
+                let (command, response) = error_response(U2fHidErrorCode::ChannelBusy);
+                queue_message(state, message.channel_id, command, response);
+                return;
+            }
+        }
+
+        let (command, response) = match command {
+            Some(U2fHidCommand::Init) => {
+                if message.data.len() != 8 {
+                    error_response(U2fHidErrorCode::InvalidMessageLength)
+                } else {
+                    let channel_id = if message.channel_id == BROADCAST_CID {
+                        let channel_id = state.next_channel_id;
+                        state.next_channel_id += 1;
+                        channel_id
+                    } else {
+                        message.channel_id
+                    };
+
+                    let mut response = ByteBuffer::new();
+                    response.write_bytes(&message.data);
+                    response.write_u32(channel_id);
+                    response.write_u8(2); // protocol version
+                    response.write_u8(0);
+                    response.write_u8(1);
+                    response.write_u8(0);
+                    response.write_u8(SOFT_HID_CAPABILITIES);
+
+                    (U2fHidCommand::Init, response.to_bytes())
+                }
+            },
+            Some(U2fHidCommand::Ping) => (U2fHidCommand::Ping, message.data),
+            Some(U2fHidCommand::Wink) => (U2fHidCommand::Wink, vec![]),
+            Some(U2fHidCommand::Lock) => {
+                if message.data.len() != 1 {
+                    error_response(U2fHidErrorCode::InvalidMessageLength)
+                } else if message.data[0] > MAX_LOCK_SECONDS {
+                    error_response(U2fHidErrorCode::InvalidParameter)
+                } else {
+                    state.lock = match message.data[0] {
+                        0 => None,
+                        seconds => Some((message.channel_id, Instant::now() + Duration::from_secs(seconds as u64))),
+                    };
+
+                    (U2fHidCommand::Lock, vec![])
+                }
+            },
+            Some(U2fHidCommand::Msg) => (U2fHidCommand::Msg, self.authenticator.process_apdu(&message.data)),
+            // requests are answered synchronously, so there is never anything to cancel
+            Some(U2fHidCommand::Cancel) => return,
+            _ => error_response(U2fHidErrorCode::InvalidCommand),
+        };
+
+        queue_message(state, message.channel_id, command, response);
+    }
+}
+
+fn error_response(code: U2fHidErrorCode) -> (U2fHidCommand, Vec<u8>) {
+    (U2fHidCommand::Error, vec![code as u8])
+}
+
+fn queue_message(state: &mut SoftHidState, channel_id: u32, command: U2fHidCommand, data: Vec<u8>) {
+    let mut data = ByteBuffer::from_bytes(&data);
+    let mut report = ByteBuffer::new();
+
+    prepare_init_packet(&mut report, channel_id, command, &mut data, state.packet_size);
+    state.output.push_back(report.to_bytes());
+
+    let mut seq: u8 = 0;
+
+    while data.get_rpos() < data.len() {
+        report.clear();
+
+        prepare_cont_packet(&mut report, channel_id, seq, &mut data, state.packet_size);
+        state.output.push_back(report.to_bytes());
+
+        seq += 1;
+    }
+}
+
+impl HidTransport for SoftHidTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().expect("soft hid state lock");
+
+        let mut data = ByteBuffer::from_bytes(report);
+
+        let message = match parse_packet(&mut data, state.packet_size)? {
+            HidPacket::Init(packet) => {
+                let len = cmp::min(packet.len, packet.payload.len());
+
+                PendingMessage {
+                    channel_id: packet.channel_id,
+                    command: packet.command,
+                    len: packet.len,
+                    data: packet.payload[0..len].to_owned(),
+                    next_seq: 0,
+                }
+            },
+            HidPacket::Cont(packet) => {
+                let mut message = match state.pending.take() {
+                    Some(message) => message,
+                    None => return Ok(report.len()),
+                };
+
+                if packet.channel_id != message.channel_id {
+                    state.pending = Some(message);
+                    return Ok(report.len());
+                }
+
+                if packet.seq != message.next_seq {
+                    let (command, response) = error_response(U2fHidErrorCode::InvalidMessageSequence);
+                    queue_message(&mut state, message.channel_id, command, response);
+                    self.output_ready.notify_all();
+                    return Ok(report.len());
+                }
+
+                let len = cmp::min(message.len - message.data.len(), packet.payload.len());
+                message.data.extend_from_slice(&packet.payload[0..len]);
+                message.next_seq += 1;
+
+                message
+            },
+        };
+
+        if message.data.len() < message.len {
+            state.pending = Some(message);
+        } else {
+            self.dispatch(&mut state, message);
+            self.output_ready.notify_all();
+        }
+
+        Ok(report.len())
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let mut state = self.state.lock().expect("soft hid state lock");
+
+        // wait like a real device would, rather than have pollers spin
+        let deadline = Instant::now() + Duration::from_millis(cmp::max(timeout_millis, 0) as u64);
+
+        while state.output.is_empty() {
+            let now = Instant::now();
+
+            if timeout_millis >= 0 && now >= deadline {
+                break;
+            }
+
+            state = if timeout_millis < 0 {
+                self.output_ready.wait(state).expect("soft hid state lock")
+            } else {
+                self.output_ready.wait_timeout(state, deadline - now).expect("soft hid state lock").0
+            };
+        }
+
+        match state.output.pop_front() {
+            Some(data) => {
+                let len = cmp::min(data.len(), report.len());
+                report[0..len].copy_from_slice(&data[0..len]);
+                Ok(len)
+            },
+            None => Ok(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ::{U2fDevice, Verify};
+    use error;
+    use std::mem;
+
+    fn device() -> U2fHidDevice<SoftHidTransport> {
+        let mut device = U2fHidDevice::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+        device.init().unwrap();
+        device
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let device = device();
+
+        assert_eq!(device.capabilities(), Some(U2fHidCapabilities {
+            wink: true,
+            lock: true,
+            cbor: false,
+            nmsg: false,
+        }));
+    }
+
+    #[test]
+    fn test_register_verifies() {
+        let device = device();
+
+        let challenge = vec![1; 32];
+        let app_param = vec![2; 32];
+
+        let response = device.register(&challenge, &app_param).unwrap();
+
+        response.verify(&challenge, &app_param).unwrap();
+    }
+
+    #[test]
+    fn test_authenticate_increments_counter() {
+        let device = device();
+
+        let app_param = vec![2; 32];
+        let registration = device.register(&vec![1; 32], &app_param).unwrap();
+
+        let first = device.authenticate(&vec![3; 32], &app_param, &registration.key_handle).unwrap();
+        let second = device.authenticate(&vec![4; 32], &app_param, &registration.key_handle).unwrap();
+
+        assert_eq!(first.counter + 1, second.counter);
+    }
+
+    #[test]
+    fn test_authenticate_rejects_foreign_key_handle() {
+        let device = device();
+
+        let registration = device.register(&vec![1; 32], &vec![2; 32]).unwrap();
+
+        match device.authenticate(&vec![3; 32], &vec![5; 32], &registration.key_handle) {
+            Err(error::Error(error::ErrorKind::HidError(ErrorKind::ErrorStatus(U2fStatusWord::WrongData)), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_register_waits_for_touch() {
+        let authenticator = SoftAuthenticator::new(&[7; 32]);
+        authenticator.set_auto_presence(false);
+
+        match authenticator.register(&vec![1; 32], &vec![2; 32]) {
+            Err(error::Error(error::ErrorKind::UserPresenceRequired, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        authenticator.touch();
+
+        authenticator.register(&vec![1; 32], &vec![2; 32]).unwrap();
+    }
+
+    #[test]
+    fn test_lock_excludes_other_channels() {
+        let transport = SoftHidTransport::new(SoftAuthenticator::new(&[7; 32]));
+
+        let mut first = U2fHidDevice::new(&transport);
+        first.init().unwrap();
+        let mut second = U2fHidDevice::new(&transport);
+        second.retry_policy = RetryPolicy::never();
+        second.init().unwrap();
+
+        {
+            let lock = first.lock(Duration::from_secs(5)).unwrap();
+            lock.ping().unwrap();
+
+            match second.ping() {
+                Err(Error(ErrorKind::ChannelBusy, _)) => {},
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        second.ping().unwrap();
+        assert!(first.lock(Duration::from_secs(11)).is_err());
+    }
+
+    #[test]
+    fn test_busy_channel_is_retried() {
+        let transport = SoftHidTransport::new(SoftAuthenticator::new(&[7; 32]));
+
+        let mut first = U2fHidDevice::new(&transport);
+        first.init().unwrap();
+        let mut second = U2fHidDevice::new(&transport);
+        second.init().unwrap();
+        second.retry_policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(200),
+        };
+
+        // held until it lapses on the device
+        mem::forget(first.lock(Duration::from_secs(1)).unwrap());
+
+        second.ping().unwrap();
+    }
+
+    #[test]
+    fn test_ping_test_up_to_largest_message() {
+        let device = device();
+
+        for &len in &[0, 1, 57, 58, max_message_len(HID_REPORT_SIZE)] {
+            let stats = device.ping_test(len, 3).unwrap();
+
+            assert_eq!((stats.payload_len, stats.round_trips), (len, 3));
+            assert!(stats.min <= stats.mean() && stats.mean() <= stats.max);
+        }
+
+        match device.echo(&vec![0; max_message_len(HID_REPORT_SIZE) + 1]) {
+            Err(Error(ErrorKind::RequestTooLong, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_register_with_small_packets() {
+        let transport = SoftHidTransport::with_packet_size(SoftAuthenticator::new(&[7; 32]), 32);
+
+        let mut device = U2fHidDevice::with_packet_size(transport, 32).unwrap();
+        device.init().unwrap();
+
+        let challenge = vec![1; 32];
+        let application = vec![2; 32];
+
+        device.register(&challenge, &application).unwrap().verify(&challenge, &application).unwrap();
+    }
+}
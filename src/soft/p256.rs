@@ -0,0 +1,619 @@
+//! Minimal NIST P-256 arithmetic and ECDSA signing for the software
+//! authenticator.
+//!
+//! This is written for clarity rather than side-channel resistance: nothing
+//! here runs in constant time, so it must not be used to protect real keys.
+
+use std::cmp::Ordering;
+use rand::Rng;
+
+use super::der;
+
+/// 256-bit unsigned integer as little-endian 32-bit limbs.
+pub type U256 = [u32; 8];
+
+const P: U256 = [
+    0xffffffff, 0xffffffff, 0xffffffff, 0x00000000,
+    0x00000000, 0x00000000, 0x00000001, 0xffffffff,
+];
+
+const N: U256 = [
+    0xfc632551, 0xf3b9cac2, 0xa7179e84, 0xbce6faad,
+    0xffffffff, 0xffffffff, 0x00000000, 0xffffffff,
+];
+
+const B: U256 = [
+    0x27d2604b, 0x3bce3c3e, 0xcc53b0f6, 0x651d06b0,
+    0x769886bc, 0xb3ebbd55, 0xaa3a93e7, 0x5ac635d8,
+];
+
+const GX: U256 = [
+    0xd898c296, 0xf4a13945, 0x2deb33a0, 0x77037d81,
+    0x63a440f2, 0xf8bce6e5, 0xe12c4247, 0x6b17d1f2,
+];
+
+const GY: U256 = [
+    0x37bf51f5, 0xcbb64068, 0x6b315ece, 0x2bce3357,
+    0x7c0f9e16, 0x8ee7eb4a, 0xfe1a7f9b, 0x4fe342e2,
+];
+
+const ZERO: U256 = [0; 8];
+const ONE: U256 = [1, 0, 0, 0, 0, 0, 0, 0];
+
+pub fn from_bytes(bytes: &[u8]) -> U256 {
+    assert_eq!(bytes.len(), 32);
+
+    let mut r = ZERO;
+    for i in 0..8 {
+        let o = 28 - i * 4;
+        r[i] = ((bytes[o] as u32) << 24) | ((bytes[o + 1] as u32) << 16) |
+            ((bytes[o + 2] as u32) << 8) | (bytes[o + 3] as u32);
+    }
+    r
+}
+
+pub fn to_bytes(a: &U256) -> Vec<u8> {
+    let mut r = Vec::with_capacity(32);
+    for i in (0..8).rev() {
+        r.push((a[i] >> 24) as u8);
+        r.push((a[i] >> 16) as u8);
+        r.push((a[i] >> 8) as u8);
+        r.push(a[i] as u8);
+    }
+    r
+}
+
+fn cmp(a: &U256, b: &U256) -> Ordering {
+    for i in (0..8).rev() {
+        match a[i].cmp(&b[i]) {
+            Ordering::Equal => continue,
+            o => return o,
+        }
+    }
+    Ordering::Equal
+}
+
+fn is_zero(a: &U256) -> bool {
+    a.iter().all(|&x| x == 0)
+}
+
+fn bit(a: &U256, i: usize) -> bool {
+    (a[i / 32] >> (i % 32)) & 1 == 1
+}
+
+/// r = a + b, returning the carry out.
+fn add_raw(a: &U256, b: &U256) -> (U256, u32) {
+    let mut r = ZERO;
+    let mut carry = 0u64;
+    for i in 0..8 {
+        let t = a[i] as u64 + b[i] as u64 + carry;
+        r[i] = t as u32;
+        carry = t >> 32;
+    }
+    (r, carry as u32)
+}
+
+/// r = a - b, returning the borrow out.
+fn sub_raw(a: &U256, b: &U256) -> (U256, u32) {
+    let mut r = ZERO;
+    let mut borrow = 0i64;
+    for i in 0..8 {
+        let t = a[i] as i64 - b[i] as i64 - borrow;
+        r[i] = t as u32;
+        borrow = if t < 0 { 1 } else { 0 };
+    }
+    (r, borrow as u32)
+}
+
+/// Arithmetic modulo an odd 256-bit modulus using Montgomery multiplication.
+struct Modulus {
+    m: U256,
+    m_inv: u32, // -m^-1 mod 2^32
+    r2: U256, // 2^512 mod m
+}
+
+impl Modulus {
+    fn new(m: U256) -> Modulus {
+        let mut inv: u32 = 1;
+        for _ in 0..5 {
+            inv = inv.wrapping_mul(2u32.wrapping_sub(m[0].wrapping_mul(inv)));
+        }
+
+        let mut modulus = Modulus {
+            m: m,
+            m_inv: inv.wrapping_neg(),
+            r2: ZERO,
+        };
+
+        let mut r2 = ONE;
+        for _ in 0..512 {
+            r2 = modulus.add(&r2, &r2);
+        }
+        modulus.r2 = r2;
+
+        modulus
+    }
+
+    fn add(&self, a: &U256, b: &U256) -> U256 {
+        let (r, carry) = add_raw(a, b);
+        if carry != 0 || cmp(&r, &self.m) != Ordering::Less {
+            sub_raw(&r, &self.m).0
+        } else {
+            r
+        }
+    }
+
+    fn sub(&self, a: &U256, b: &U256) -> U256 {
+        let (r, borrow) = sub_raw(a, b);
+        if borrow != 0 {
+            add_raw(&r, &self.m).0
+        } else {
+            r
+        }
+    }
+
+    fn reduce(&self, a: &U256) -> U256 {
+        if cmp(a, &self.m) != Ordering::Less {
+            sub_raw(a, &self.m).0
+        } else {
+            *a
+        }
+    }
+
+    fn mul(&self, a: &U256, b: &U256) -> U256 {
+        let mut t = [0u32; 10];
+
+        for i in 0..8 {
+            let mut c = 0u64;
+            for j in 0..8 {
+                let s = t[j] as u64 + (a[j] as u64) * (b[i] as u64) + c;
+                t[j] = s as u32;
+                c = s >> 32;
+            }
+            let s = t[8] as u64 + c;
+            t[8] = s as u32;
+            t[9] = (s >> 32) as u32;
+
+            let q = t[0].wrapping_mul(self.m_inv);
+            let mut c = (t[0] as u64 + (q as u64) * (self.m[0] as u64)) >> 32;
+            for j in 1..8 {
+                let s = t[j] as u64 + (q as u64) * (self.m[j] as u64) + c;
+                t[j - 1] = s as u32;
+                c = s >> 32;
+            }
+            let s = t[8] as u64 + c;
+            t[7] = s as u32;
+            t[8] = t[9] + (s >> 32) as u32;
+        }
+
+        let mut r = ZERO;
+        r.copy_from_slice(&t[0..8]);
+
+        if t[8] != 0 || cmp(&r, &self.m) != Ordering::Less {
+            sub_raw(&r, &self.m).0
+        } else {
+            r
+        }
+    }
+
+    fn to_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &self.r2)
+    }
+
+    fn from_mont(&self, a: &U256) -> U256 {
+        self.mul(a, &ONE)
+    }
+
+    /// Inverse of a Montgomery-form element by Fermat's little theorem.
+    fn inv(&self, a: &U256) -> U256 {
+        let e = sub_raw(&self.m, &[2, 0, 0, 0, 0, 0, 0, 0]).0;
+        let mut r = self.to_mont(&ONE);
+        for i in (0..256).rev() {
+            r = self.mul(&r, &r);
+            if bit(&e, i) {
+                r = self.mul(&r, a);
+            }
+        }
+        r
+    }
+}
+
+/// Point in Jacobian coordinates with Montgomery-form field elements.
+/// The point at infinity has z = 0.
+#[derive(Clone, Copy)]
+struct Point {
+    x: U256,
+    y: U256,
+    z: U256,
+}
+
+struct Curve {
+    p: Modulus,
+    n: Modulus,
+    b: U256,
+    g: Point,
+}
+
+impl Curve {
+    fn new() -> Curve {
+        let p = Modulus::new(P);
+        let n = Modulus::new(N);
+
+        let g = Point {
+            x: p.to_mont(&GX),
+            y: p.to_mont(&GY),
+            z: p.to_mont(&ONE),
+        };
+        let b = p.to_mont(&B);
+
+        Curve { p: p, n: n, b: b, g: g }
+    }
+
+    fn infinity() -> Point {
+        Point { x: ZERO, y: ZERO, z: ZERO }
+    }
+
+    fn double(&self, a: &Point) -> Point {
+        let f = &self.p;
+
+        if is_zero(&a.z) {
+            return *a;
+        }
+
+        let delta = f.mul(&a.z, &a.z);
+        let gamma = f.mul(&a.y, &a.y);
+        let beta = f.mul(&a.x, &gamma);
+
+        let t = f.mul(&f.sub(&a.x, &delta), &f.add(&a.x, &delta));
+        let alpha = f.add(&f.add(&t, &t), &t);
+
+        let beta2 = f.add(&beta, &beta);
+        let beta4 = f.add(&beta2, &beta2);
+        let beta8 = f.add(&beta4, &beta4);
+
+        let x3 = f.sub(&f.mul(&alpha, &alpha), &beta8);
+
+        let yz = f.add(&a.y, &a.z);
+        let z3 = f.sub(&f.sub(&f.mul(&yz, &yz), &gamma), &delta);
+
+        let gamma_sq = f.mul(&gamma, &gamma);
+        let gamma_sq2 = f.add(&gamma_sq, &gamma_sq);
+        let gamma_sq4 = f.add(&gamma_sq2, &gamma_sq2);
+        let gamma_sq8 = f.add(&gamma_sq4, &gamma_sq4);
+
+        let y3 = f.sub(&f.mul(&alpha, &f.sub(&beta4, &x3)), &gamma_sq8);
+
+        Point { x: x3, y: y3, z: z3 }
+    }
+
+    fn add(&self, a: &Point, b: &Point) -> Point {
+        let f = &self.p;
+
+        if is_zero(&a.z) {
+            return *b;
+        }
+        if is_zero(&b.z) {
+            return *a;
+        }
+
+        let z1z1 = f.mul(&a.z, &a.z);
+        let z2z2 = f.mul(&b.z, &b.z);
+        let u1 = f.mul(&a.x, &z2z2);
+        let u2 = f.mul(&b.x, &z1z1);
+        let s1 = f.mul(&f.mul(&a.y, &b.z), &z2z2);
+        let s2 = f.mul(&f.mul(&b.y, &a.z), &z1z1);
+
+        let h = f.sub(&u2, &u1);
+        let s = f.sub(&s2, &s1);
+
+        if is_zero(&h) {
+            if is_zero(&s) {
+                return self.double(a);
+            }
+            return Self::infinity();
+        }
+
+        let r = f.add(&s, &s);
+        let h2 = f.add(&h, &h);
+        let i = f.mul(&h2, &h2);
+        let j = f.mul(&h, &i);
+        let v = f.mul(&u1, &i);
+
+        let x3 = f.sub(&f.sub(&f.mul(&r, &r), &j), &f.add(&v, &v));
+
+        let s1j = f.mul(&s1, &j);
+        let y3 = f.sub(&f.mul(&r, &f.sub(&v, &x3)), &f.add(&s1j, &s1j));
+
+        let zz = f.add(&a.z, &b.z);
+        let z3 = f.mul(&f.sub(&f.sub(&f.mul(&zz, &zz), &z1z1), &z2z2), &h);
+
+        Point { x: x3, y: y3, z: z3 }
+    }
+
+    fn mul(&self, k: &U256, point: &Point) -> Point {
+        let mut r = Self::infinity();
+        for i in (0..256).rev() {
+            r = self.double(&r);
+            if bit(k, i) {
+                r = self.add(&r, point);
+            }
+        }
+        r
+    }
+
+    /// Affine (x, y) in normal form.
+    fn to_affine(&self, a: &Point) -> (U256, U256) {
+        let f = &self.p;
+
+        let z_inv = f.inv(&a.z);
+        let z_inv2 = f.mul(&z_inv, &z_inv);
+        let z_inv3 = f.mul(&z_inv2, &z_inv);
+
+        (f.from_mont(&f.mul(&a.x, &z_inv2)), f.from_mont(&f.mul(&a.y, &z_inv3)))
+    }
+
+    fn is_on_curve(&self, x: &U256, y: &U256) -> bool {
+        let f = &self.p;
+
+        let x = f.to_mont(x);
+        let y = f.to_mont(y);
+
+        let y2 = f.mul(&y, &y);
+        let x3 = f.mul(&f.mul(&x, &x), &x);
+        let x3 = f.sub(&f.sub(&f.sub(&x3, &x), &x), &x);
+
+        cmp(&y2, &f.add(&x3, &self.b)) == Ordering::Equal
+    }
+}
+
+/// ECDSA P-256 private key.
+pub struct PrivateKey {
+    d: U256,
+    public_key: Vec<u8>,
+}
+
+impl PrivateKey {
+    /// Builds a key from a 32 byte big-endian scalar, which must be in [1, n-1].
+    pub fn from_scalar(bytes: &[u8]) -> Option<PrivateKey> {
+        let d = from_bytes(bytes);
+
+        if is_zero(&d) || cmp(&d, &N) != Ordering::Less {
+            return None;
+        }
+
+        let curve = Curve::new();
+        let (x, y) = curve.to_affine(&curve.mul(&d, &curve.g));
+        debug_assert!(curve.is_on_curve(&x, &y));
+
+        let mut public_key = vec![0x04];
+        public_key.extend(to_bytes(&x));
+        public_key.extend(to_bytes(&y));
+
+        Some(PrivateKey {
+            d: d,
+            public_key: public_key,
+        })
+    }
+
+    /// Uncompressed public key point: 0x04 || x || y.
+    pub fn public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// Signs a SHA-256 digest, returning the DER encoded (r, s) pair.
+    pub fn sign_digest<R: Rng>(&self, digest: &[u8], rng: &mut R) -> Vec<u8> {
+        loop {
+            let mut k = [0u8; 32];
+            rng.fill_bytes(&mut k);
+
+            if let Some(signature) = self.sign_digest_with_nonce(digest, &k) {
+                return signature;
+            }
+        }
+    }
+
+    /// Signs with the nonce `k`, or fails if `k` is out of range or gives a
+    /// zero r or s, in which case another nonce must be tried.
+    fn sign_digest_with_nonce(&self, digest: &[u8], k: &[u8]) -> Option<Vec<u8>> {
+        let curve = Curve::new();
+        let n = &curve.n;
+
+        let k = from_bytes(k);
+        if is_zero(&k) || cmp(&k, &N) != Ordering::Less {
+            return None;
+        }
+
+        let e = n.to_mont(&n.reduce(&from_bytes(digest)));
+        let d = n.to_mont(&self.d);
+
+        let (x, _) = curve.to_affine(&curve.mul(&k, &curve.g));
+        let r = n.reduce(&x);
+        if is_zero(&r) {
+            return None;
+        }
+
+        let k_inv = n.inv(&n.to_mont(&k));
+        let rd = n.mul(&n.to_mont(&r), &d);
+        let s = n.from_mont(&n.mul(&k_inv, &n.add(&e, &rd)));
+        if is_zero(&s) {
+            return None;
+        }
+
+        Some(encode_signature(&to_bytes(&r), &to_bytes(&s)))
+    }
+}
+
+fn encode_signature(r: &[u8], s: &[u8]) -> Vec<u8> {
+    der::sequence(&[der::integer(r), der::integer(s)])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len() / 2).map(|i| u8::from_str_radix(&s[2 * i..2 * i + 2], 16).unwrap()).collect()
+    }
+
+    /// Splits a DER signature into r and s.
+    fn decode_signature(signature: &[u8]) -> (U256, U256) {
+        assert_eq!(signature[0], der::TAG_SEQUENCE);
+        assert_eq!(signature[1] as usize, signature.len() - 2);
+
+        let mut rest = &signature[2..];
+        let mut integers = vec![];
+
+        while !rest.is_empty() {
+            assert_eq!(rest[0], der::TAG_INTEGER);
+            let len = rest[1] as usize;
+            let value = &rest[2..2 + len];
+            // drop the sign byte, then pad to 32 bytes
+            let value = if value.len() == 33 { &value[1..] } else { value };
+            let mut bytes = vec![0; 32 - value.len()];
+            bytes.extend_from_slice(value);
+            integers.push(from_bytes(&bytes));
+            rest = &rest[2 + len..];
+        }
+
+        assert_eq!(integers.len(), 2);
+        (integers[0], integers[1])
+    }
+
+    /// Textbook ECDSA verification, independent of the signing code path.
+    fn verify_digest(public_key: &[u8], digest: &[u8], signature: &[u8]) -> bool {
+        let curve = Curve::new();
+        let n = &curve.n;
+        let f = &curve.p;
+
+        let (r, s) = decode_signature(signature);
+        if is_zero(&r) || is_zero(&s) || cmp(&r, &N) != Ordering::Less || cmp(&s, &N) != Ordering::Less {
+            return false;
+        }
+
+        let q = Point {
+            x: f.to_mont(&from_bytes(&public_key[1..33])),
+            y: f.to_mont(&from_bytes(&public_key[33..65])),
+            z: f.to_mont(&ONE),
+        };
+
+        let e = n.to_mont(&n.reduce(&from_bytes(digest)));
+        let w = n.inv(&n.to_mont(&s));
+        let u1 = n.from_mont(&n.mul(&e, &w));
+        let u2 = n.from_mont(&n.mul(&n.to_mont(&r), &w));
+
+        let point = curve.add(&curve.mul(&u1, &curve.g), &curve.mul(&u2, &q));
+        if is_zero(&point.z) {
+            return false;
+        }
+
+        let (x, _) = curve.to_affine(&point);
+        cmp(&n.reduce(&x), &r) == Ordering::Equal
+    }
+
+    #[test]
+    fn test_public_key_for_scalar_one_is_generator() {
+        let mut d = [0u8; 32];
+        d[31] = 1;
+
+        let key = PrivateKey::from_scalar(&d).unwrap();
+
+        assert_eq!(&key.public_key()[1..33], &to_bytes(&GX)[..]);
+        assert_eq!(&key.public_key()[33..65], &to_bytes(&GY)[..]);
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_scalar() {
+        assert!(PrivateKey::from_scalar(&[0u8; 32]).is_none());
+        assert!(PrivateKey::from_scalar(&to_bytes(&N)).is_none());
+        assert!(PrivateKey::from_scalar(&[0xff; 32]).is_none());
+    }
+
+    #[test]
+    fn test_point_multiplication_vectors() {
+        // (k, x, y) of k * G
+        let vectors = [
+            ("0000000000000000000000000000000000000000000000000000000000000002",
+             "7cf27b188d034f7e8a52380304b51ac3c08969e277f21b35a60b48fc47669978",
+             "07775510db8ed040293d9ac69f7430dbba7dade63ce982299e04b79d227873d1"),
+            ("0000000000000000000000000000000000000000000000000000000000000003",
+             "5ecbe4d1a6330a44c8f7ef951d4bf165e6c6b721efada985fb41661bc6e7fd6c",
+             "8734640c4998ff7e374b06ce1a64a2ecd82ab036384fb83d9a79b127a27d5032"),
+            // 112233445566778899
+            ("000000000000000000000000000000000000000000000000018ebbb95eed0e13",
+             "339150844ec15234807fe862a86be77977dbfb3ae3d96f4c22795513aeaab82f",
+             "b1c14ddfdc8ec1b2583f51e85a5eb3a155840f2034730e9b5ada38b674336a21"),
+            ("8000000000000000000000000000000000000000000000000000000000000000",
+             "77b20a912e6b23135066e911891524bc4efe3560e3e92350b52dec8f375f2b54",
+             "a3dc291825cea3f7f7b10bfcdd038a72df623da1e850e0f1caa801fcd6cc67ff"),
+            // n - 2
+            ("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc63254f",
+             "7cf27b188d034f7e8a52380304b51ac3c08969e277f21b35a60b48fc47669978",
+             "f888aaee24712fc0d6c26539608bcf244582521ac3167dd661fb4862dd878c2e"),
+            // n - 1
+            ("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632550",
+             "6b17d1f2e12c4247f8bce6e563a440f277037d812deb33a0f4a13945d898c296",
+             "b01cbd1c01e58065711814b583f061e9d431cca994cea1313449bf97c840ae0a"),
+        ];
+
+        for &(k, x, y) in vectors.iter() {
+            let key = PrivateKey::from_scalar(&hex(k)).unwrap();
+
+            assert_eq!(&key.public_key()[1..33], &hex(x)[..], "x of {} * G", k);
+            assert_eq!(&key.public_key()[33..65], &hex(y)[..], "y of {} * G", k);
+        }
+    }
+
+    #[test]
+    fn test_rfc6979_signature() {
+        // RFC 6979 A.2.5, P-256 with SHA-256, message "sample"
+        let key = PrivateKey::from_scalar(&hex("c9afa9d845ba75166b5c215767b1d6934e50c3db36e89b127b8a622b120f6721")).unwrap();
+        let digest = hex("af2bdbe1aa9b6ec1e2ade1d694f41fc71a831d0268e9891562113d8a62add1bf");
+        let k = hex("a6e3c57dd01abe90086538398355dd4c3b17aa873382b0f24d6129493d8aad60");
+
+        assert_eq!(&key.public_key()[1..33], &hex("60fed4ba255a9d31c961eb74c6356d68c049b8923b61fa6ce669622e60f29fb6")[..]);
+        assert_eq!(&key.public_key()[33..65], &hex("7903fe1008b8bc99a41ae9e95628bc64f2f1b20c2d7e9f5177a3c294d4462299")[..]);
+
+        let signature = key.sign_digest_with_nonce(&digest, &k).unwrap();
+
+        assert_eq!(signature, encode_signature(
+            &hex("efd48b2aacb6a8fd1140dd9cd45e81d69d2c877b56aaf991c34d0ea84eaf3716"),
+            &hex("f7cb1c942d657c41d436c7a1b6e29f65f3e900dbb9aff4064dc4ab2f843acda8")));
+        assert!(verify_digest(key.public_key(), &digest, &signature));
+    }
+
+    #[test]
+    fn test_rejects_out_of_range_nonce() {
+        let key = PrivateKey::from_scalar(&[7; 32]).unwrap();
+
+        assert!(key.sign_digest_with_nonce(&[1; 32], &[0; 32]).is_none());
+        assert!(key.sign_digest_with_nonce(&[1; 32], &to_bytes(&N)).is_none());
+        assert!(key.sign_digest_with_nonce(&[1; 32], &[0xff; 32]).is_none());
+    }
+
+    #[test]
+    fn test_sign_verify_round_trip() {
+        let scalars = [
+            hex("0000000000000000000000000000000000000000000000000000000000000001"),
+            hex("ffffffff00000000ffffffffffffffffbce6faada7179e84f3b9cac2fc632550"),
+            vec![7; 32],
+        ];
+        // including digests that are zero, and above n so they must be reduced
+        let digests = [vec![0; 32], vec![0xff; 32], to_bytes(&N), vec![0x5a; 32]];
+
+        let mut rng = rand::thread_rng();
+
+        for scalar in scalars.iter() {
+            let key = PrivateKey::from_scalar(scalar).unwrap();
+
+            for digest in digests.iter() {
+                let signature = key.sign_digest(digest, &mut rng);
+                assert!(verify_digest(key.public_key(), digest, &signature));
+
+                let mut tampered = digest.clone();
+                tampered[31] ^= 1;
+                assert!(!verify_digest(key.public_key(), &tampered, &signature));
+            }
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use super::error::*;
+
+const ITEM_TYPE_MAIN: u8 = 0;
+const ITEM_TYPE_GLOBAL: u8 = 1;
+const ITEM_TYPE_LOCAL: u8 = 2;
+
+const MAIN_INPUT: u8 = 0x8;
+const MAIN_OUTPUT: u8 = 0x9;
+const MAIN_COLLECTION: u8 = 0xa;
+const MAIN_END_COLLECTION: u8 = 0xc;
+
+const GLOBAL_USAGE_PAGE: u8 = 0x0;
+const GLOBAL_REPORT_SIZE: u8 = 0x7;
+const GLOBAL_REPORT_ID: u8 = 0x8;
+const GLOBAL_REPORT_COUNT: u8 = 0x9;
+const GLOBAL_PUSH: u8 = 0xa;
+const GLOBAL_POP: u8 = 0xb;
+
+const LOCAL_USAGE: u8 = 0x0;
+
+/// Top-level application collection declared by a HID report descriptor.
+///
+/// Report sizes are in bytes, excluding the report ID, and are the largest
+/// report of each kind if the collection declares several.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HidCollection {
+    pub usage_page: u16,
+    pub usage: u16,
+    pub input_report_size: usize,
+    pub output_report_size: usize,
+}
+
+impl HidCollection {
+    /// U2FHID packet size for this collection, which needs input and output
+    /// reports of the same size.
+    pub fn packet_size(&self) -> Result<usize> {
+        if self.input_report_size != self.output_report_size {
+            bail!(ErrorKind::InvalidReportDescriptor);
+        }
+
+        Ok(self.input_report_size)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct GlobalState {
+    usage_page: u16,
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Adds the fields of one main item to the size of report `report_id`,
+/// failing on sizes no real report could have.
+fn add_report_bits(reports: &mut Vec<(u8, u32)>, report_id: u8, global: &GlobalState) -> Result<()> {
+    let bits = match global.report_size.checked_mul(global.report_count) {
+        Some(bits) => bits,
+        None => bail!(ErrorKind::InvalidReportDescriptor),
+    };
+
+    if let Some(report) = reports.iter_mut().find(|report| report.0 == report_id) {
+        report.1 = match report.1.checked_add(bits) {
+            Some(total) => total,
+            None => bail!(ErrorKind::InvalidReportDescriptor),
+        };
+        return Ok(());
+    }
+
+    reports.push((report_id, bits));
+
+    Ok(())
+}
+
+fn report_size(reports: &[(u8, u32)]) -> usize {
+    reports.iter().map(|report| (report.1 / 8 + if report.1 % 8 != 0 { 1 } else { 0 }) as usize).max().unwrap_or(0)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReportDescriptor {
+    pub collections: Vec<HidCollection>,
+}
+
+impl ReportDescriptor {
+    pub fn parse(data: &[u8]) -> Result<ReportDescriptor> {
+        let mut collections = vec![];
+
+        let mut global = GlobalState::default();
+        let mut global_stack = vec![];
+        let mut usages: Vec<u32> = vec![];
+        let mut depth = 0;
+
+        // (report id, bits) of the current top-level collection
+        let mut input_reports = vec![];
+        let mut output_reports = vec![];
+
+        let mut i = 0;
+
+        while i < data.len() {
+            let prefix = data[i];
+
+            if prefix == 0xfe {
+                // long item: prefix, size, tag, data
+                if i + 1 >= data.len() {
+                    bail!(ErrorKind::InvalidReportDescriptor);
+                }
+                i += 3 + data[i + 1] as usize;
+                continue;
+            }
+
+            let size = match prefix & 0x3 {
+                3 => 4,
+                n => n as usize,
+            };
+            let item_type = (prefix >> 2) & 0x3;
+            let tag = prefix >> 4;
+
+            if i + 1 + size > data.len() {
+                bail!(ErrorKind::InvalidReportDescriptor);
+            }
+
+            let mut value: u32 = 0;
+            for (n, b) in data[i + 1..i + 1 + size].iter().enumerate() {
+                value |= (*b as u32) << (8 * n);
+            }
+
+            match (item_type, tag) {
+                (ITEM_TYPE_GLOBAL, GLOBAL_USAGE_PAGE) => {
+                    global.usage_page = value as u16;
+                },
+                (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_SIZE) => {
+                    global.report_size = value;
+                },
+                (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_ID) => {
+                    global.report_id = value as u8;
+                },
+                (ITEM_TYPE_GLOBAL, GLOBAL_REPORT_COUNT) => {
+                    global.report_count = value;
+                },
+                (ITEM_TYPE_GLOBAL, GLOBAL_PUSH) => {
+                    global_stack.push(global);
+                },
+                (ITEM_TYPE_GLOBAL, GLOBAL_POP) => {
+                    global = match global_stack.pop() {
+                        Some(global) => global,
+                        None => bail!(ErrorKind::InvalidReportDescriptor),
+                    };
+                },
+                (ITEM_TYPE_LOCAL, LOCAL_USAGE) => {
+                    // a 4 byte usage carries its own usage page in the high word
+                    usages.push(if size == 4 { value } else { ((global.usage_page as u32) << 16) | value });
+                },
+                (ITEM_TYPE_MAIN, MAIN_INPUT) => {
+                    add_report_bits(&mut input_reports, global.report_id, &global)?;
+                },
+                (ITEM_TYPE_MAIN, MAIN_OUTPUT) => {
+                    add_report_bits(&mut output_reports, global.report_id, &global)?;
+                },
+                (ITEM_TYPE_MAIN, MAIN_COLLECTION) => {
+                    if depth == 0 {
+                        let usage = usages.first().cloned().unwrap_or((global.usage_page as u32) << 16);
+
+                        collections.push(HidCollection {
+                            usage_page: (usage >> 16) as u16,
+                            usage: usage as u16,
+                            input_report_size: 0,
+                            output_report_size: 0,
+                        });
+                    }
+                    depth += 1;
+                },
+                (ITEM_TYPE_MAIN, MAIN_END_COLLECTION) => {
+                    if depth == 0 {
+                        bail!(ErrorKind::InvalidReportDescriptor);
+                    }
+                    depth -= 1;
+
+                    if depth == 0 {
+                        if let Some(collection) = collections.last_mut() {
+                            collection.input_report_size = report_size(&input_reports);
+                            collection.output_report_size = report_size(&output_reports);
+                        }
+
+                        input_reports.clear();
+                        output_reports.clear();
+                    }
+                },
+                _ => {}
+            }
+
+            // local items only apply to the next main item
+            if item_type == ITEM_TYPE_MAIN {
+                usages.clear();
+            }
+
+            i += 1 + size;
+        }
+
+        Ok(ReportDescriptor {
+            collections: collections,
+        })
+    }
+
+    pub fn has_usage(&self, usage_page: u16, usage: u16) -> bool {
+        self.collections.iter().any(|c| c.usage_page == usage_page && c.usage == usage)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use usb::hid::{FIDO_USAGE_PAGE, U2F_USAGE};
+
+    const FIDO_REPORT_DESCRIPTOR: &'static [u8] = &[
+        0x06, 0xd0, 0xf1, // usage page (fido)
+        0x09, 0x01, // usage (u2f hid)
+        0xa1, 0x01, // collection (application)
+        0x09, 0x20, // usage (input report data)
+        0x15, 0x00, // logical minimum (0)
+        0x26, 0xff, 0x00, // logical maximum (255)
+        0x75, 0x08, // report size (8)
+        0x95, 0x40, // report count (64)
+        0x81, 0x02, // input (data, var, abs)
+        0x09, 0x21, // usage (output report data)
+        0x15, 0x00, // logical minimum (0)
+        0x26, 0xff, 0x00, // logical maximum (255)
+        0x75, 0x08, // report size (8)
+        0x95, 0x40, // report count (64)
+        0x91, 0x02, // output (data, var, abs)
+        0xc0, // end collection
+    ];
+
+    #[test]
+    fn test_parse_fido_descriptor() {
+        let descriptor = ReportDescriptor::parse(FIDO_REPORT_DESCRIPTOR).unwrap();
+
+        assert_eq!(descriptor.collections, vec![HidCollection {
+            usage_page: FIDO_USAGE_PAGE,
+            usage: U2F_USAGE,
+            input_report_size: 64,
+            output_report_size: 64,
+        }]);
+        assert!(descriptor.has_usage(FIDO_USAGE_PAGE, U2F_USAGE));
+        assert_eq!(descriptor.collections[0].packet_size().unwrap(), 64);
+    }
+
+    #[test]
+    fn test_parse_report_sizes() {
+        let mut data = FIDO_REPORT_DESCRIPTOR.to_owned();
+        data[17] = 0x20; // 32 byte input reports
+
+        let descriptor = ReportDescriptor::parse(&data).unwrap();
+
+        assert_eq!(descriptor.collections[0].input_report_size, 32);
+        assert_eq!(descriptor.collections[0].output_report_size, 64);
+        assert!(descriptor.collections[0].packet_size().is_err());
+    }
+
+    #[test]
+    fn test_parse_huge_report_count() {
+        // report count (0xffffffff) in place of 64
+        let mut data = FIDO_REPORT_DESCRIPTOR[0..16].to_owned();
+        data.extend_from_slice(&[0x97, 0xff, 0xff, 0xff, 0xff]);
+        data.extend_from_slice(&FIDO_REPORT_DESCRIPTOR[18..]);
+
+        assert!(ReportDescriptor::parse(&data).is_err());
+
+        // two inputs of 2^31 bits each
+        let mut data = FIDO_REPORT_DESCRIPTOR[0..14].to_owned();
+        data.extend_from_slice(&[0x77, 0x00, 0x00, 0x01, 0x00, 0x97, 0x00, 0x80, 0x00, 0x00, 0x81, 0x02, 0x81, 0x02, 0xc0]);
+
+        assert!(ReportDescriptor::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_parse_truncated_descriptor() {
+        assert!(ReportDescriptor::parse(&FIDO_REPORT_DESCRIPTOR[0..2]).is_err());
+    }
+}
@@ -1,20 +1,30 @@
-use hidapi;
 use raw;
-use usb::hid::U2fHidErrorCode;
+use usb::hid::{U2fHidCommand, U2fHidErrorCode};
 use raw::frame::U2fStatusWord;
 
+#[cfg(feature = "hidapi")]
+pub use hidapi::HidError;
+
+#[cfg(not(feature = "hidapi"))]
+pub type HidError = String;
+
 error_chain! {
     links {
         FramingError(raw::error::Error, raw::error::ErrorKind);
     }
 
+    foreign_links {
+        Io(::std::io::Error);
+        Json(::serde_json::Error);
+    }
+
     errors {
         RequestTooLong {
             description("request too long")
             display("request too long")
         }
 
-        Hid(e: hidapi::HidError) {
+        Hid(e: HidError) {
             description("hid error")
             display("hid error: {}", e)
         }
@@ -39,6 +49,11 @@ error_chain! {
             display("hid packet is too small")
         }
 
+        HidPacketTooLarge {
+            description("hid packet is too large")
+            display("hid packet is too large")
+        }
+
         UnknownChannelId {
             description("unknown channel id")
             display("unknown channel id")
@@ -63,5 +78,125 @@ error_chain! {
             description("unknown error status")
             display("unknown error status: {}", status)
         }
+
+        TransportDisconnected {
+            description("transport disconnected")
+            display("transport disconnected")
+        }
+
+        UnsupportedCommand(command: U2fHidCommand) {
+            description("command not supported by device")
+            display("command not supported by device: {:?}", command)
+        }
+
+        InvalidVendorCommand(command: u8) {
+            description("not a vendor command")
+            display("not a vendor command: {:#x}", command)
+        }
+
+        ChannelBusy {
+            description("channel busy")
+            display("channel busy")
+        }
+
+        MessageTimedOut {
+            description("message timed out")
+            display("message timed out")
+        }
+
+        CommandRequiresChannelLock {
+            description("command requires channel lock")
+            display("command requires channel lock")
+        }
+
+        InvalidLockDuration {
+            description("invalid lock duration")
+            display("invalid lock duration")
+        }
+
+        InvalidMessageSequence {
+            description("invalid message sequence")
+            display("invalid message sequence")
+        }
+
+        Timeout {
+            description("timed out")
+            display("timed out")
+        }
+
+        Cancelled {
+            description("cancelled")
+            display("cancelled")
+        }
+
+        InvalidRecording(line: usize) {
+            description("invalid recording")
+            display("invalid recording at line {}", line)
+        }
+
+        ReplayMismatch(index: usize) {
+            description("client diverged from recorded session")
+            display("client diverged from recorded session at report {}", index)
+        }
+
+        InvalidCapture(reason: String) {
+            description("invalid capture")
+            display("invalid capture: {}", reason)
+        }
+
+        UnsupportedLinkType(link_type: u32) {
+            description("unsupported capture link type")
+            display("unsupported capture link type: {}", link_type)
+        }
+
+        ChannelReassigned(channel_id: u32) {
+            description("device moved us to another channel")
+            display("device moved us to channel {}, resync to adopt it", channel_id)
+        }
+
+        PingMismatch(offset: usize) {
+            description("ping echoed different data")
+            display("ping echoed different data at byte {}", offset)
+        }
+
+        DevicesOpen {
+            description("devices are still open")
+            display("devices are still open, close them before re-enumerating")
+        }
+
+        InvalidReportDescriptor {
+            description("invalid report descriptor")
+            display("invalid report descriptor")
+        }
+    }
+}
+
+impl ErrorKind {
+    /// Whether the request may succeed if sent again unchanged, i.e. the
+    /// device turned it away rather than rejecting it.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            ErrorKind::ChannelBusy | ErrorKind::MessageTimedOut => true,
+            _ => false,
+        }
+    }
+
+    /// Whether the failed transaction may have left reports behind, or the
+    /// device part way through a message, so that the channel should be
+    /// resynchronised before it is used again.
+    pub fn needs_resync(&self) -> bool {
+        match *self {
+            ErrorKind::Io(_) |
+            ErrorKind::Hid(_) |
+            ErrorKind::FramingError(_) |
+            ErrorKind::UnexpectedPacket |
+            ErrorKind::InvalidMessageSequence |
+            ErrorKind::UnknownHidCommand(_) |
+            ErrorKind::HidError(U2fHidErrorCode::InvalidMessageSequence) |
+            ErrorKind::HidError(U2fHidErrorCode::InvalidMessageLength) |
+            ErrorKind::ChannelReassigned(_) |
+            ErrorKind::Timeout => true,
+            _ => false,
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,206 @@
+//! Choosing between several attached FIDO devices.
+//!
+//! A `DeviceFilter` narrows `FidoExt::fido_devices` down by USB identifiers.
+//! Saved to a file, it also serves as the user's preferred device, so that
+//! with a work key and a personal key plugged in the same one is picked on
+//! every run.
+
+use std::fs::File;
+use std::path::Path;
+#[cfg(feature = "hidapi")]
+use hidapi::HidDeviceInfo;
+use serde_json;
+
+use super::error::*;
+
+/// USB details of a device that a `DeviceFilter` can match on.
+pub trait DeviceIdentity {
+    fn path(&self) -> &str;
+    fn vendor_id(&self) -> u16;
+    fn product_id(&self) -> u16;
+    fn serial_number(&self) -> Option<&str>;
+    fn manufacturer(&self) -> Option<&str>;
+}
+
+#[cfg(feature = "hidapi")]
+impl DeviceIdentity for HidDeviceInfo {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_ref().map(|serial| serial.as_str())
+    }
+
+    fn manufacturer(&self) -> Option<&str> {
+        self.manufacturer_string.as_ref().map(|manufacturer| manufacturer.as_str())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceIdentity for super::hidraw::HidrawDeviceInfo {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_ref().map(|serial| serial.as_str())
+    }
+
+    fn manufacturer(&self) -> Option<&str> {
+        self.manufacturer_string.as_ref().map(|manufacturer| manufacturer.as_str())
+    }
+}
+
+/// Matches the devices for which every field that is set agrees.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceFilter {
+    pub vendor_id: Option<u16>,
+    pub product_id: Option<u16>,
+    pub serial_number: Option<String>,
+    pub manufacturer: Option<String>,
+    /// e.g. `/dev/hidraw3`, which may change when the device is replugged.
+    pub path: Option<String>,
+}
+
+impl DeviceFilter {
+    /// A filter that matches every device.
+    pub fn any() -> DeviceFilter {
+        DeviceFilter::default()
+    }
+
+    /// A filter that picks out `device` again later: by its serial number
+    /// if it has one, otherwise by its path.
+    pub fn for_device<D>(device: &D) -> DeviceFilter where D: DeviceIdentity {
+        let serial_number = device.serial_number().map(|serial| serial.to_owned());
+
+        DeviceFilter {
+            vendor_id: Some(device.vendor_id()),
+            product_id: Some(device.product_id()),
+            path: if serial_number.is_none() { Some(device.path().to_owned()) } else { None },
+            serial_number: serial_number,
+            manufacturer: None,
+        }
+    }
+
+    pub fn matches<D>(&self, device: &D) -> bool where D: DeviceIdentity {
+        self.vendor_id.map(|vendor_id| vendor_id == device.vendor_id()).unwrap_or(true) &&
+            self.product_id.map(|product_id| product_id == device.product_id()).unwrap_or(true) &&
+            self.path.as_ref().map(|path| path == device.path()).unwrap_or(true) &&
+            self.serial_number.as_ref().map(|serial| Some(serial.as_str()) == device.serial_number()).unwrap_or(true) &&
+            self.manufacturer.as_ref().map(|manufacturer| Some(manufacturer.as_str()) == device.manufacturer()).unwrap_or(true)
+    }
+
+    pub fn filter<D>(&self, devices: Vec<D>) -> Vec<D> where D: DeviceIdentity {
+        devices.into_iter()
+            .filter(|device| self.matches(device))
+            .collect::<Vec<D>>()
+    }
+
+    /// Reads a filter saved with `save`.
+    pub fn load<P>(path: P) -> Result<DeviceFilter> where P: AsRef<Path> {
+        let file = File::open(path)?;
+
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn save<P>(&self, path: P) -> Result<()> where P: AsRef<Path> {
+        let mut file = File::create(path)?;
+
+        Ok(serde_json::to_writer_pretty(&mut file, self)?)
+    }
+}
+
+/// Picks the device matching `preferred`, or `None` if it is not attached.
+/// Without a preference, picks the first device in a stable order, so that
+/// the choice does not depend on enumeration order.
+pub fn select_device<D>(devices: Vec<D>, preferred: Option<&DeviceFilter>) -> Option<D> where D: DeviceIdentity {
+    let mut devices = devices;
+
+    devices.sort_by(|a, b| {
+        (a.vendor_id(), a.product_id(), a.serial_number(), a.path())
+            .cmp(&(b.vendor_id(), b.product_id(), b.serial_number(), b.path()))
+    });
+
+    match preferred {
+        Some(preferred) => devices.into_iter().find(|device| preferred.matches(device)),
+        None => devices.into_iter().next(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Device(&'static str, u16, Option<&'static str>);
+
+    impl DeviceIdentity for Device {
+        fn path(&self) -> &str {
+            self.0
+        }
+
+        fn vendor_id(&self) -> u16 {
+            0x1050
+        }
+
+        fn product_id(&self) -> u16 {
+            self.1
+        }
+
+        fn serial_number(&self) -> Option<&str> {
+            self.2
+        }
+
+        fn manufacturer(&self) -> Option<&str> {
+            Some("Yubico")
+        }
+    }
+
+    #[test]
+    fn test_filter_and_select() {
+        let work = Device("/dev/hidraw4", 0x0407, Some("work"));
+        let personal = Device("/dev/hidraw2", 0x0407, Some("personal"));
+        let other = Device("/dev/hidraw3", 0x0120, None);
+        let devices = vec![work.clone(), personal.clone(), other.clone()];
+
+        let filter = DeviceFilter {
+            product_id: Some(0x0407),
+            manufacturer: Some("Yubico".to_owned()),
+            .. DeviceFilter::any()
+        };
+        assert_eq!(filter.filter(devices.clone()), vec![work.clone(), personal.clone()]);
+
+        // the same one is picked whatever order the devices are listed in
+        let preferred = DeviceFilter::for_device(&work);
+        let mut reversed = devices.clone();
+        reversed.reverse();
+        assert_eq!(select_device(devices.clone(), Some(&preferred)), Some(work.clone()));
+        assert_eq!(select_device(reversed, Some(&preferred)), Some(work.clone()));
+
+        // without a serial number the path is all there is to go on
+        assert_eq!(DeviceFilter::for_device(&other).path, Some("/dev/hidraw3".to_owned()));
+
+        // no stand-in for a device that is not attached
+        let absent = DeviceFilter::for_device(&Device("/dev/hidraw9", 0x0407, Some("lost")));
+        assert_eq!(select_device(devices.clone(), Some(&absent)), None);
+        assert_eq!(select_device(devices.clone(), None), Some(other.clone()));
+        assert_eq!(select_device(Vec::<Device>::new(), None), None);
+    }
+}
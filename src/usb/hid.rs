@@ -1,14 +1,19 @@
 use bytebuffer::*;
 use std::cmp;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use rand;
 use rand::Rng;
 use enum_primitive::FromPrimitive;
 
 use super::error::*;
-use hidapi::*;
+use super::transport::*;
 use raw::frame::*;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct U2fHidDeviceInfo {
     pub protocol_version: u8,
     pub major_device_version: u8,
@@ -17,17 +22,153 @@ pub struct U2fHidDeviceInfo {
     pub raw_capabilities: u8,
 }
 
-pub struct U2fHidDevice<'a> {
+impl U2fHidDeviceInfo {
+    pub fn capabilities(&self) -> U2fHidCapabilities {
+        U2fHidCapabilities::from_u8(self.raw_capabilities)
+    }
+}
+
+pub const CAPABILITY_WINK: u8 = 0x01;
+pub const CAPABILITY_LOCK: u8 = 0x02;
+pub const CAPABILITY_CBOR: u8 = 0x04;
+pub const CAPABILITY_NMSG: u8 = 0x08;
+
+/// Capability flags reported in the INIT response.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct U2fHidCapabilities {
+    pub wink: bool,
+    pub lock: bool,
+    /// Implements CTAPHID_CBOR (FIDO2).
+    pub cbor: bool,
+    /// Does not implement CTAPHID_MSG, i.e. cannot do U2F.
+    pub nmsg: bool,
+}
+
+impl U2fHidCapabilities {
+    pub fn from_u8(raw: u8) -> U2fHidCapabilities {
+        U2fHidCapabilities {
+            wink: raw & CAPABILITY_WINK != 0,
+            lock: raw & CAPABILITY_LOCK != 0,
+            cbor: raw & CAPABILITY_CBOR != 0,
+            nmsg: raw & CAPABILITY_NMSG != 0,
+        }
+    }
+
+    pub fn supports(&self, command: U2fHidCommand) -> bool {
+        match command {
+            U2fHidCommand::Wink => self.wink,
+            U2fHidCommand::Lock => self.lock,
+            U2fHidCommand::Msg => !self.nmsg,
+            _ => true,
+        }
+    }
+}
+
+pub struct U2fHidDevice<T> {
     pub packet_size: usize,
     pub channel_id: u32,
-    pub hid_device: HidDevice<'a>,
+    pub hid_device: T,
     pub u2f_info: Option<U2fHidDeviceInfo>,
+    pub retry_policy: RetryPolicy,
+    pub timeouts: Timeouts,
+    cancelled: Arc<AtomicBool>,
+    keepalive_callback: Option<Box<Fn(KeepaliveStatus) + Send + Sync>>,
+}
+
+/// How `U2fHidDevice::command` retries requests that failed with a
+/// retryable error, such as the device being busy with another channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled after every attempt.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl RetryPolicy {
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            initial_backoff: Duration::from_millis(0),
+            max_backoff: Duration::from_millis(0),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(1000),
+        }
+    }
+}
+
+/// Timeouts applied by `U2fHidDevice` while waiting for the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timeouts {
+    /// Longest wait for any single report.
+    pub report: Duration,
+    /// Deadline for a whole command, including retries and keepalives.
+    pub transaction: Option<Duration>,
+    /// How long to wait for a touch once the device asks for one.
+    pub user_presence: Duration,
+}
+
+impl Timeouts {
+    /// Timeouts for a request that must complete within `timeout`, such as
+    /// one carrying `timeoutSeconds`.
+    pub fn with_deadline(timeout: Duration) -> Timeouts {
+        Timeouts {
+            transaction: Some(timeout),
+            user_presence: timeout,
+            .. Timeouts::default()
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            report: Duration::from_millis(3000),
+            transaction: None,
+            user_presence: Duration::from_secs(30),
+        }
+    }
+}
+
+fn duration_millis(duration: Duration) -> i32 {
+    cmp::min(duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1000000, i32::max_value() as u64) as i32
 }
 
 pub const BROADCAST_CID: u32 = 0xffffffff;
 pub const HID_REPORT_SIZE: usize = 64;
+pub const INIT_HEADER_SIZE: usize = 7;
+pub const CONT_HEADER_SIZE: usize = 5;
+pub const MAX_CONT_PACKETS: usize = 128;
+
+/// Largest message that fits in one init and `MAX_CONT_PACKETS`
+/// continuation packets of `packet_size` bytes, and whose length fits the
+/// 16 bit BCNT field.
+pub fn max_message_len(packet_size: usize) -> usize {
+    let len = (packet_size - INIT_HEADER_SIZE) + MAX_CONT_PACKETS * (packet_size - CONT_HEADER_SIZE);
+
+    cmp::min(len, u16::max_value() as usize)
+}
 pub const FIDO_USAGE_PAGE: u16 = 0xf1d0;
 pub const U2F_USAGE: u16 = 0x1;
+pub const MAX_LOCK_SECONDS: u8 = 10;
+pub const VENDOR_COMMAND_FIRST: u8 = 0xc0;
+pub const VENDOR_COMMAND_LAST: u8 = 0xff;
+
+/// How often a blocked read wakes up to check for cancellation.
+pub const CANCEL_POLL_MILLIS: i32 = 100;
+
+/// CTAP2_ERR_KEEPALIVE_CANCEL, reported by devices for a request aborted by
+/// `U2fHidCommand::Cancel`.
+pub const KEEPALIVE_CANCEL: u8 = 0x2d;
 
 enum_from_primitive! {
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -37,10 +178,20 @@ pub enum U2fHidCommand {
     Lock = 0x84,
     Init = 0x86,
     Wink = 0x88,
+    Cancel = 0x91,
+    Keepalive = 0xbb,
     Error = 0xbf,
 }
 }
 
+enum_from_primitive! {
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum KeepaliveStatus {
+    Processing = 0x1,
+    UserPresenceNeeded = 0x2,
+}
+}
+
 enum_from_primitive! {
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum U2fHidErrorCode {
@@ -60,40 +211,170 @@ pub trait SmartCard {
     fn send_apdu<E>(&self, cmd: CommandAPDU) -> Result<ResponseAPDU> where E: RequestEncoder;
 }
 
-impl <'a> SmartCard for U2fHidDevice<'a> {
+impl <T> SmartCard for U2fHidDevice<T> where T: HidTransport {
     fn send_apdu<E>(&self, cmd: CommandAPDU) -> Result<ResponseAPDU> where E: RequestEncoder {
         let mut bb = ByteBuffer::new();
 
         E::encode(&mut bb, cmd)?;
 
-        println!("encoded {} bytes", bb.len());
-
         self.command(U2fHidCommand::Msg, &mut bb)?;
 
-        let response = Decoder::decode(&mut bb)?;
+        decode_apdu_response(&mut bb)
+    }
+}
 
-        match U2fStatusWord::from_u16(response.status) {
-            Some(U2fStatusWord::NoError) => Ok(response),
-            Some(err) => Err(ErrorKind::ErrorStatus(err).into()),
-            None => Err(ErrorKind::UnknownErrorStatus(response.status).into())
-        }
+/// Checks that a ping was echoed unchanged, failing with `PingMismatch` at
+/// the first byte that differs.
+pub fn check_echo(payload: &[u8], echoed: &[u8]) -> Result<()> {
+    if echoed.len() != payload.len() {
+        bail!(ErrorKind::PingMismatch(cmp::min(echoed.len(), payload.len())));
     }
+
+    if let Some(offset) = echoed.iter().zip(payload.iter()).position(|(a, b)| a != b) {
+        bail!(ErrorKind::PingMismatch(offset));
+    }
+
+    Ok(())
 }
 
-impl <'a> U2fHidDevice<'a> {
+/// Decodes the APDU carried by a MSG response, failing on any status other
+/// than `NoError`.
+pub fn decode_apdu_response(bb: &mut ByteBuffer) -> Result<ResponseAPDU> {
+    let response = Decoder::decode(bb)?;
 
-    pub fn ping(&self) -> Result<()> {
+    match U2fStatusWord::from_u16(response.status) {
+        Some(U2fStatusWord::NoError) => Ok(response),
+        Some(err) => Err(ErrorKind::ErrorStatus(err).into()),
+        None => Err(ErrorKind::UnknownErrorStatus(response.status).into())
+    }
+}
+
+impl <T> U2fHidDevice<T> where T: HidTransport {
+    pub fn new(hid_device: T) -> U2fHidDevice<T> {
+        U2fHidDevice {
+            packet_size: HID_REPORT_SIZE,
+            channel_id: BROADCAST_CID,
+            hid_device: hid_device,
+            u2f_info: None,
+            retry_policy: RetryPolicy::default(),
+            timeouts: Timeouts::default(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            keepalive_callback: None,
+        }
+    }
+
+    /// Creates a device whose reports are `packet_size` bytes, as declared
+    /// by its report descriptor (see `HidCollection::packet_size`). FIDO
+    /// devices use full speed interrupt endpoints, so reports are at most
+    /// `HID_REPORT_SIZE` bytes.
+    pub fn with_packet_size(hid_device: T, packet_size: usize) -> Result<U2fHidDevice<T>> {
+        if packet_size <= INIT_HEADER_SIZE {
+            bail!(ErrorKind::HidPacketTooSmall);
+        }
+
+        if packet_size > HID_REPORT_SIZE {
+            bail!(ErrorKind::HidPacketTooLarge);
+        }
+
+        let mut device = Self::new(hid_device);
+        device.packet_size = packet_size;
+
+        Ok(device)
+    }
+
+    /// Sets a callback for the KEEPALIVE frames a device sends while a
+    /// request is pending, e.g. to prompt for a touch on
+    /// `KeepaliveStatus::UserPresenceNeeded`.
+    pub fn set_keepalive_callback<F>(&mut self, callback: F) where F: Fn(KeepaliveStatus) + Send + Sync + 'static {
+        self.keepalive_callback = Some(Box::new(callback));
+    }
+
+    /// Returns a handle that can cancel this device's in-flight command from
+    /// another thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        CancelHandle {
+            cancelled: self.cancelled.clone(),
+        }
+    }
+
+    /// Sends CTAPHID_CANCEL on our channel. The device answers the pending
+    /// request, not the cancel itself.
+    pub fn cancel(&self) -> Result<()> {
         let mut buf = ByteBuffer::new();
-        buf.write_u8(0);
 
-        self.command(U2fHidCommand::Ping, &mut buf)?;
+        self.send_request(U2fHidCommand::Cancel, &mut buf)
+    }
 
-        println!("read {} bytes", buf.len());
+    pub fn ping(&self) -> Result<()> {
+        self.echo(&[0])?;
 
         Ok(())
     }
 
+    /// Pings with `payload`, which may take up to `max_message_len` bytes,
+    /// and checks that the device echoes it unchanged. Returns the round
+    /// trip time.
+    pub fn echo(&self, payload: &[u8]) -> Result<Duration> {
+        let mut buf = ByteBuffer::from_bytes(payload);
+
+        let started = Instant::now();
+        self.command(U2fHidCommand::Ping, &mut buf)?;
+        let elapsed = started.elapsed();
+
+        check_echo(payload, &buf.to_bytes())?;
+
+        Ok(elapsed)
+    }
+
+    /// Echoes `round_trips` pings of `payload_len` random bytes, for
+    /// qualifying a device and the USB path to it.
+    pub fn ping_test(&self, payload_len: usize, round_trips: u32) -> Result<PingStats> {
+        let mut rng = rand::thread_rng();
+        let mut payload = vec![0; payload_len];
+
+        let mut stats = PingStats {
+            payload_len: payload_len,
+            round_trips: 0,
+            min: Duration::from_secs(0),
+            max: Duration::from_secs(0),
+            total: Duration::from_secs(0),
+        };
+
+        for _ in 0..round_trips {
+            for x in payload.iter_mut() {
+                *x = rng.gen();
+            }
+
+            let elapsed = self.echo(&payload)?;
+
+            if stats.round_trips == 0 || elapsed < stats.min {
+                stats.min = elapsed;
+            }
+
+            stats.max = cmp::max(stats.max, elapsed);
+            stats.total += elapsed;
+            stats.round_trips += 1;
+        }
+
+        Ok(stats)
+    }
+
+    /// Capabilities reported by `init`, if it has run.
+    pub fn capabilities(&self) -> Option<U2fHidCapabilities> {
+        self.u2f_info.as_ref().map(|info| info.capabilities())
+    }
+
+    pub fn supports_cbor(&self) -> bool {
+        self.capabilities().map(|capabilities| capabilities.cbor).unwrap_or(false)
+    }
+
+    /// Winks, if the device can. Devices without the WINK capability are
+    /// left alone.
     pub fn wink(&self) -> Result<()> {
+        if !self.capabilities().map(|capabilities| capabilities.wink).unwrap_or(true) {
+            return Ok(());
+        }
+
         let mut buf = ByteBuffer::new();
 
         self.command(U2fHidCommand::Wink, &mut buf)?;
@@ -101,6 +382,44 @@ impl <'a> U2fHidDevice<'a> {
         Ok(())
     }
 
+    /// Locks the device to our channel for up to `duration` (at most
+    /// `MAX_LOCK_SECONDS`), so that a sequence of commands cannot be
+    /// interleaved with other channels' traffic. The lock is released when
+    /// the returned guard is dropped; it is not renewed, so it also lapses
+    /// on the device once `duration` has passed. Fails with
+    /// `UnsupportedCommand` if the device lacks the LOCK capability.
+    pub fn lock(&self, duration: Duration) -> Result<U2fHidLock<T>> {
+        if !self.capabilities().map(|capabilities| capabilities.lock).unwrap_or(true) {
+            bail!(ErrorKind::UnsupportedCommand(U2fHidCommand::Lock));
+        }
+
+        let seconds = duration.as_secs() + if duration.subsec_nanos() != 0 { 1 } else { 0 };
+
+        if seconds == 0 || seconds > MAX_LOCK_SECONDS as u64 {
+            bail!(ErrorKind::InvalidLockDuration);
+        }
+
+        self.send_lock(seconds as u8)?;
+
+        Ok(U2fHidLock {
+            device: self,
+        })
+    }
+
+    /// Releases a lock held by this channel.
+    pub fn unlock(&self) -> Result<()> {
+        self.send_lock(0)
+    }
+
+    fn send_lock(&self, seconds: u8) -> Result<()> {
+        let mut buf = ByteBuffer::new();
+        buf.write_u8(seconds);
+
+        self.command(U2fHidCommand::Lock, &mut buf)?;
+
+        Ok(())
+    }
+
     pub fn message(&self, msg: &[u8]) -> Result<Vec<u8>> {
         let mut buf = ByteBuffer::new();
 
@@ -126,28 +445,81 @@ impl <'a> U2fHidDevice<'a> {
     pub fn init(&mut self) -> Result<()> {
         self.channel_id = BROADCAST_CID;
 
-        let mut buf = ByteBuffer::new();
+        let (channel_id, info) = self.send_init(None)?;
+
+        self.channel_id = channel_id;
+        self.u2f_info = Some(info);
+
+        Ok(())
+    }
+
+    /// Recovers the channel after an aborted transaction: discards stale
+    /// reports and runs INIT again with a fresh nonce. An allocated channel
+    /// is synchronised in place, which also aborts anything the device was
+    /// still doing for it; if that fails a new channel is allocated.
+    pub fn resync(&mut self) -> Result<()> {
+        self.drain()?;
+
+        if self.channel_id != BROADCAST_CID {
+            match self.send_init(None) {
+                Ok((channel_id, info)) => {
+                    self.channel_id = channel_id;
+                    self.u2f_info = Some(info);
+                    return Ok(());
+                },
+                // fall back to a new channel
+                Err(_) => {},
+            }
+
+            self.drain()?;
+        }
+
+        self.init()
+    }
 
+    /// Discards the reports already waiting to be read, such as the rest of
+    /// a response nobody is waiting for any more. Returns how many there
+    /// were.
+    pub fn drain(&self) -> Result<usize> {
+        let mut report = vec![0; self.packet_size];
+        let mut drained = 0;
+
+        while self.hid_device.read_report(report.as_mut_slice(), 0)? != 0 {
+            drained += 1;
+        }
+
+        Ok(drained)
+    }
+
+    /// Sends INIT on our channel and waits up to `timeouts.report`, or until
+    /// `deadline` if that comes first, for the response carrying our nonce.
+    /// Returns the channel id it assigns.
+    fn send_init(&self, deadline: Option<Instant>) -> Result<(u32, U2fHidDeviceInfo)> {
         let nonce = Self::nonce();
-        buf.write_bytes(nonce.as_slice());
+        let report_deadline = Instant::now() + self.timeouts.report;
+        let deadline = deadline.map(|deadline| cmp::min(deadline, report_deadline)).unwrap_or(report_deadline);
 
-        self.send_request(U2fHidCommand::Init, &mut buf)?;
-        
-        buf.clear();
+        self.send_request(U2fHidCommand::Init, &mut ByteBuffer::from_bytes(&nonce))?;
+
+        let mut buf = ByteBuffer::new();
 
         loop {
-            self.recv_response(U2fHidCommand::Init, &mut buf)?;
+            buf.clear();
+
+            self.recv_response_until(U2fHidCommand::Init as u8, &mut buf, Some(deadline))?;
 
             if buf.len() < 17 {
                 bail!(ErrorKind::InitResponseTooSmall);
             }
 
+            // answers to someone else's INIT, or to one of ours that was
+            // abandoned, until the deadline passes
             let recvd_nonce = buf.read_bytes(8);
             if recvd_nonce != nonce {
                 continue;
             }
 
-            self.channel_id = buf.read_u32();
+            let channel_id = buf.read_u32();
 
             let info = U2fHidDeviceInfo {
                 protocol_version: buf.read_u8(),
@@ -157,55 +529,137 @@ impl <'a> U2fHidDevice<'a> {
                 raw_capabilities: buf.read_u8(),
             };
 
-            self.u2f_info = Some(info);
+            return Ok((channel_id, info));
+        }
+    }
 
-            break;
+    /// Best effort resync of an allocated channel from `command`, which
+    /// cannot take a new channel id: drains stale reports and synchronises
+    /// the channel in place, giving up at the transaction's `deadline`.
+    /// Fails with `ChannelReassigned` if the device moved us to another
+    /// channel, which only `resync` can adopt.
+    pub fn recover(&self, deadline: Option<Instant>) -> Result<()> {
+        if self.drain().is_err() {
+            return Ok(());
+        }
+
+        if self.channel_id == BROADCAST_CID || deadline.map(|deadline| Instant::now() >= deadline).unwrap_or(false) {
+            return Ok(());
+        }
+
+        // if this fails too, the caller hears about the original error
+        if let Ok((channel_id, _)) = self.send_init(deadline) {
+            if channel_id != self.channel_id {
+                bail!(ErrorKind::ChannelReassigned(channel_id));
+            }
         }
 
         Ok(())
     }
 
+    /// Sends `buf` and replaces its contents with the response, retrying
+    /// according to `retry_policy`.
     pub fn command(&self, command: U2fHidCommand, buf: &mut ByteBuffer) -> Result<()> {
-        self.send_request(command, buf)?;
+        if let Some(capabilities) = self.capabilities() {
+            if !capabilities.supports(command) {
+                bail!(ErrorKind::UnsupportedCommand(command));
+            }
+        }
 
-        buf.clear();
+        self.raw_command(command as u8, buf)
+    }
 
-        self.recv_response(command, buf)?;
+    /// Sends a vendor specific command (`VENDOR_COMMAND_FIRST` to
+    /// `VENDOR_COMMAND_LAST`) and returns the payload of the device's
+    /// response, which must carry the same command byte.
+    pub fn vendor_command(&self, command: u8, payload: &[u8]) -> Result<Vec<u8>> {
+        if command < VENDOR_COMMAND_FIRST {
+            bail!(ErrorKind::InvalidVendorCommand(command));
+        }
 
-        Ok(())
+        let mut buf = ByteBuffer::from_bytes(payload);
+
+        self.raw_command(command, &mut buf)?;
+
+        Ok(buf.to_bytes())
+    }
+
+    fn raw_command(&self, command: u8, buf: &mut ByteBuffer) -> Result<()> {
+        let request = buf.to_bytes()[buf.get_rpos()..].to_owned();
+        let deadline = self.timeouts.transaction.map(|timeout| Instant::now() + timeout);
+
+        let mut attempt = 1;
+        let mut backoff = self.retry_policy.initial_backoff;
+
+        // only a cancel raised during this command applies to it
+        self.cancelled.store(false, Ordering::SeqCst);
+
+        loop {
+            // cancelled while backing off between attempts
+            if self.cancelled.swap(false, Ordering::SeqCst) {
+                bail!(ErrorKind::Cancelled);
+            }
+
+            // leftovers of an earlier transaction would be taken for our response
+            self.drain()?;
+
+            buf.clear();
+
+            let result = self.send_raw_request(command, &mut ByteBuffer::from_bytes(&request))
+                .and_then(|_| self.recv_response_until(command, buf, deadline));
+
+            match result {
+                Err(ref e) if e.kind().is_retryable() && attempt < self.retry_policy.max_attempts => {},
+                Err(e) => {
+                    if e.kind().needs_resync() {
+                        // a reassigned channel needs the caller's attention
+                        // more than the error that led to it
+                        self.recover(deadline)?;
+                    }
+
+                    return Err(e);
+                },
+                result => return result,
+            }
+
+            if let Some(deadline) = deadline {
+                if Instant::now() + backoff >= deadline {
+                    bail!(ErrorKind::Timeout);
+                }
+            }
+
+            thread::sleep(backoff);
+
+            attempt += 1;
+            backoff = cmp::min(backoff * 2, self.retry_policy.max_backoff);
+        }
     }
 
     pub fn send_request(&self, command: U2fHidCommand, request_data: &mut ByteBuffer) -> Result<()> {
-        if (request_data.len() - request_data.get_rpos()) >= 7609 {
+        self.send_raw_request(command as u8, request_data)
+    }
+
+    fn send_raw_request(&self, command: u8, request_data: &mut ByteBuffer) -> Result<()> {
+        if (request_data.len() - request_data.get_rpos()) > max_message_len(self.packet_size) {
             bail!(ErrorKind::RequestTooLong);
         }
 
         let mut request = ByteBuffer::new();
 
-        request.write_u8(0x0); // hid report number
-
-        prepare_init_packet(&mut request, self.channel_id, command, request_data, self.packet_size);
-
-        println!("sending {} bytes", request.len());
-        println!("sending {:?}", request.to_bytes());
+        prepare_raw_init_packet(&mut request, self.channel_id, command, request_data, self.packet_size);
 
         // send init packet
-        self.hid_device.write(&request.to_bytes()[..])?;
+        self.hid_device.write_report(&request.to_bytes()[..])?;
 
         let mut seq: u8 = 0;
 
         while request_data.get_rpos() < request_data.len() {
             request.clear();
 
-            request.write_u8(0x0); // hid report number
-
             prepare_cont_packet(&mut request, self.channel_id, seq, request_data, self.packet_size);
 
-            println!("sending {} bytes", request.len());
-            println!("sending {:?}", request.to_bytes());
-
             // send cont packet
-            self.hid_device.write(&request.to_bytes()[..])?;
+            self.hid_device.write_report(&request.to_bytes()[..])?;
 
             seq += 1;
         }
@@ -213,66 +667,356 @@ impl <'a> U2fHidDevice<'a> {
         Ok(())
     }
 
-    pub fn recv_response(&self, command: U2fHidCommand, response: &mut ByteBuffer) -> Result<()> {    
-        let mut data = ByteBuffer::new();
+    /// Reads one report, waking up every `CANCEL_POLL_MILLIS` to forward a
+    /// cancellation to the device.
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32, cancel_sent: &mut bool) -> Result<usize> {
+        let mut remaining = timeout_millis;
+
+        loop {
+            if !*cancel_sent && self.cancelled.swap(false, Ordering::SeqCst) {
+                self.cancel()?;
+                *cancel_sent = true;
+            }
+
+            let wait = cmp::min(remaining, CANCEL_POLL_MILLIS);
+            let bytes = self.hid_device.read_report(report, wait)?;
+
+            remaining -= wait;
+
+            if bytes != 0 || remaining <= 0 {
+                return Ok(bytes);
+            }
+        }
+    }
+
+    fn keepalive(&self, frame: &HidInitPacket) -> Option<KeepaliveStatus> {
+        let status = frame.payload.first().and_then(|status| KeepaliveStatus::from_u8(*status));
+
+        match (status, &self.keepalive_callback) {
+            (Some(status), &Some(ref callback)) => callback(status),
+            // unknown statuses still mean the device is busy
+            _ => {}
+        }
+
+        status
+    }
+
+    /// Timeout for the next read: the report timeout, cut short by `deadline`.
+    fn read_timeout(&self, deadline: Option<Instant>) -> Result<i32> {
+        let timeout = duration_millis(self.timeouts.report);
+
+        match deadline {
+            Some(deadline) => {
+                let now = Instant::now();
+
+                if now >= deadline {
+                    bail!(ErrorKind::Timeout);
+                }
+
+                Ok(cmp::min(timeout, duration_millis(deadline - now)))
+            },
+            None => Ok(timeout),
+        }
+    }
+
+    pub fn recv_response(&self, command: U2fHidCommand, response: &mut ByteBuffer) -> Result<()> {
+        let deadline = self.timeouts.transaction.map(|timeout| Instant::now() + timeout);
+
+        self.recv_response_until(command as u8, response, deadline)
+    }
+
+    fn recv_response_until(&self, command: u8, response: &mut ByteBuffer, deadline: Option<Instant>) -> Result<()> {
+        let mut reader = ResponseReader::new(command, deadline);
+        let mut report = vec![0; self.packet_size];
+
+        loop {
+            let timeout = self.read_timeout(deadline)?;
+            let bytes = self.read_report(report.as_mut_slice(), timeout, &mut reader.cancel_sent)?;
+
+            if bytes == 0 {
+                bail!(ErrorKind::Timeout);
+            }
+
+            if let Some(payload) = self.feed_response(&mut reader, &report[0..bytes])? {
+                response.write_bytes(&payload);
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends a request and returns a reader for its response, to be driven
+    /// with `poll_response` instead of blocking in `command`. Neither
+    /// retries nor capability checks apply.
+    pub fn start_request(&self, command: u8, request: &[u8]) -> Result<ResponseReader> {
+        // only a cancel raised during this request applies to it
+        self.cancelled.store(false, Ordering::SeqCst);
+
+        let deadline = self.timeouts.transaction.map(|timeout| Instant::now() + timeout);
+
+        self.start_request_until(command, request, deadline)
+    }
+
+    /// Sends the request again, for another attempt at a transaction that
+    /// began with `start_request` and ends at `deadline`. Fails with
+    /// `Cancelled` if it was cancelled in between.
+    pub fn start_request_until(&self, command: u8, request: &[u8], deadline: Option<Instant>) -> Result<ResponseReader> {
+        if self.cancelled.swap(false, Ordering::SeqCst) {
+            bail!(ErrorKind::Cancelled);
+        }
+
+        self.drain()?;
+        self.send_raw_request(command, &mut ByteBuffer::from_bytes(request))?;
+
+        let mut reader = ResponseReader::new(command, deadline);
+        reader.report_deadline = Some(Instant::now() + self.timeouts.report);
+
+        Ok(reader)
+    }
+
+    /// Consumes the reports available right now, without blocking, and
+    /// returns the response payload once it is complete.
+    pub fn poll_response(&self, reader: &mut ResponseReader) -> Result<Option<Vec<u8>>> {
+        self.poll_response_from(reader, |report| self.hid_device.read_report(report, 0))
+    }
+
+    /// Like `poll_response`, for callers that read the device themselves:
+    /// reports are taken from `next_report`, which returns 0 once there are
+    /// none left.
+    pub fn poll_response_from<F>(&self, reader: &mut ResponseReader, mut next_report: F) -> Result<Option<Vec<u8>>>
+        where F: FnMut(&mut [u8]) -> Result<usize>
+    {
+        if !reader.cancel_sent && self.cancelled.swap(false, Ordering::SeqCst) {
+            self.cancel()?;
+            reader.cancel_sent = true;
+        }
+
+        let now = Instant::now();
+
+        // the same limits as the blocking path: the whole transaction, the
+        // silence between two reports, and the wait for a touch
+        let expired = [reader.deadline, reader.report_deadline, reader.user_presence_deadline].iter()
+            .any(|deadline| deadline.map(|deadline| now >= deadline).unwrap_or(false));
 
-        // read init packet
+        if expired {
+            // the timeout is what the caller needs to hear about
+            let _ = self.cancel();
+
+            bail!(ErrorKind::Timeout);
+        }
+
+        let mut report = vec![0; self.packet_size];
+
+        loop {
+            let bytes = next_report(report.as_mut_slice())?;
 
-        let mut report = vec![0; HID_REPORT_SIZE];
-        let bytes = self.hid_device.read_timeout(report.as_mut_slice(), 3000 /* millis */)?;
-        println!("read {} bytes", bytes);
-        println!("read {:?}", report.as_slice());
-        data.write_bytes(&report[0..bytes]);
+            if bytes == 0 {
+                return Ok(None);
+            }
 
-        let init_frame = parse_init_packet(&mut data, self.packet_size)?;
+            reader.report_deadline = Some(Instant::now() + self.timeouts.report);
 
-        if init_frame.channel_id != self.channel_id {
-            return Err(ErrorKind::UnknownChannelId.into());
+            if let Some(payload) = self.feed_response(reader, &report[0..bytes])? {
+                return Ok(Some(payload));
+            }
         }
+    }
+
+    /// Adds one report to the response being reassembled by `reader`.
+    fn feed_response(&self, reader: &mut ResponseReader, report: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut data = ByteBuffer::from_bytes(report);
+
+        let fragment = match parse_packet(&mut data, self.packet_size)? {
+            HidPacket::Init(frame) => {
+                // other applications may be talking to the device at the same time
+                if frame.channel_id != self.channel_id {
+                    return Ok(None);
+                }
+
+                if reader.message.is_some() {
+                    // the device abandoned the message to report an error
+                    if frame.command != U2fHidCommand::Error as u8 {
+                        bail!(ErrorKind::UnexpectedPacket);
+                    }
+                } else if frame.command == U2fHidCommand::Keepalive as u8 && reader.command != U2fHidCommand::Keepalive as u8 {
+                    // the device is still working on our request, keep waiting
+                    if self.keepalive(&frame) == Some(KeepaliveStatus::UserPresenceNeeded) {
+                        let now = Instant::now();
+                        let presence_deadline = *reader.user_presence_deadline.get_or_insert(now + self.timeouts.user_presence);
+
+                        if now >= presence_deadline {
+                            self.cancel()?;
+                            bail!(ErrorKind::Timeout);
+                        }
+                    }
+                    return Ok(None);
+                }
+
+                reader.payload.clear();
+                reader.payload_remaining = frame.len;
 
-        let mut payload_remaining = init_frame.len;
-        let fragment = &init_frame.payload[..];
-        let fragment_len = cmp::min(fragment.len(), payload_remaining);
+                let fragment = frame.payload.clone();
+                reader.message = Some((frame, 0));
+                fragment
+            },
+            HidPacket::Cont(frame) => {
+                if frame.channel_id != self.channel_id {
+                    return Ok(None);
+                }
 
-        response.write_bytes(&fragment[0..fragment_len]);
-        payload_remaining -= fragment_len;
+                let next_seq = match reader.message {
+                    Some((_, ref mut next_seq)) => next_seq,
+                    None => return Ok(None),
+                };
 
-        while payload_remaining != 0 {
-            data.clear();
+                if frame.seq != *next_seq {
+                    bail!(ErrorKind::InvalidMessageSequence);
+                }
 
-            // read cont packet
+                *next_seq += 1;
+                frame.payload
+            },
+        };
 
-            let bytes = self.hid_device.read_timeout(report.as_mut_slice(), 3000 /* millis */)?;
-            println!("read {} bytes", bytes);
-            println!("read {:?}", report.as_slice());
-            data.write_bytes(&report[0..bytes]);
+        let fragment_len = cmp::min(fragment.len(), reader.payload_remaining);
+
+        reader.payload.extend_from_slice(&fragment[0..fragment_len]);
+        reader.payload_remaining -= fragment_len;
+
+        if reader.payload_remaining != 0 {
+            return Ok(None);
+        }
 
-            let frame = parse_cont_packet(&mut data, self.packet_size)?;
+        let init_frame = reader.message.take().expect("init frame").0;
+        let payload = ::std::mem::replace(&mut reader.payload, vec![]);
 
-            let fragment = &frame.payload[..];
-            let fragment_len = cmp::min(fragment.len(), payload_remaining);
-            
-            response.write_bytes(&fragment[0..fragment_len]);
-            payload_remaining -= fragment_len;
+        // whatever the device answered, the request was cancelled
+        if reader.cancel_sent {
+            bail!(ErrorKind::Cancelled);
         }
 
         let recvd_command = U2fHidCommand::from_u8(init_frame.command);
         if recvd_command == Some(U2fHidCommand::Error) {
-            if response.len() != 0 {
-                let code = response.read_u8();
-                if let Some(code) = U2fHidErrorCode::from_u8(code) {
-                    bail!(ErrorKind::HidError(code));
-                } else {
-                    bail!(ErrorKind::HidUnknownError(code));
+            if let Some(&code) = payload.first() {
+                if code == KEEPALIVE_CANCEL {
+                    bail!(ErrorKind::Cancelled);
+                }
+                match U2fHidErrorCode::from_u8(code) {
+                    Some(U2fHidErrorCode::ChannelBusy) => bail!(ErrorKind::ChannelBusy),
+                    Some(U2fHidErrorCode::MessageTimedOut) => bail!(ErrorKind::MessageTimedOut),
+                    Some(U2fHidErrorCode::CommandRequiresChannelLock) => bail!(ErrorKind::CommandRequiresChannelLock),
+                    Some(code) => bail!(ErrorKind::HidError(code)),
+                    None => bail!(ErrorKind::HidUnknownError(code)),
                 }
             }
             bail!(ErrorKind::HidUnknownError(0));
-        } else if recvd_command != Some(command) {
-            println!("received response with mismatched command: {:?}", init_frame);
+        } else if init_frame.command != reader.command {
             bail!(ErrorKind::UnknownHidCommand(init_frame.command));
         }
 
-        Ok(())
+        Ok(Some(payload))
+    }
+}
+
+/// Round trip times measured by `U2fHidDevice::ping_test`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PingStats {
+    pub payload_len: usize,
+    pub round_trips: u32,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl PingStats {
+    pub fn mean(&self) -> Duration {
+        if self.round_trips == 0 {
+            return Duration::from_secs(0);
+        }
+
+        self.total / self.round_trips
+    }
+
+    /// Payload bytes moved per second, counting both the request and the
+    /// echo.
+    pub fn bytes_per_second(&self) -> f64 {
+        let secs = self.total.as_secs() as f64 + self.total.subsec_nanos() as f64 / 1e9;
+
+        if secs == 0.0 {
+            return 0.0;
+        }
+
+        (2 * self.payload_len) as f64 * self.round_trips as f64 / secs
+    }
+}
+
+/// Reassembly state for the response to one request, see
+/// `U2fHidDevice::start_request`.
+pub struct ResponseReader {
+    command: u8,
+    deadline: Option<Instant>,
+    // when `poll_response` gives up on a device that has gone quiet
+    report_deadline: Option<Instant>,
+    cancel_sent: bool,
+    user_presence_deadline: Option<Instant>,
+    // init frame of the message being reassembled, and the next expected seq
+    message: Option<(HidInitPacket, u8)>,
+    payload: Vec<u8>,
+    payload_remaining: usize,
+}
+
+impl ResponseReader {
+    /// When the whole transaction times out, if it does.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    fn new(command: u8, deadline: Option<Instant>) -> ResponseReader {
+        ResponseReader {
+            command: command,
+            deadline: deadline,
+            report_deadline: None,
+            cancel_sent: false,
+            user_presence_deadline: None,
+            message: None,
+            payload: vec![],
+            payload_remaining: 0,
+        }
+    }
+}
+
+/// Cancels the in-flight command of a `U2fHidDevice` from another thread.
+///
+/// A cancel raised while no command is in flight has no effect.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Channel lock held on a `U2fHidDevice`, released on drop.
+///
+/// Commands can be issued through the guard while it is held.
+pub struct U2fHidLock<'a, T: 'a> where T: HidTransport {
+    device: &'a U2fHidDevice<T>,
+}
+
+impl <'a, T> Deref for U2fHidLock<'a, T> where T: HidTransport {
+    type Target = U2fHidDevice<T>;
+
+    fn deref(&self) -> &U2fHidDevice<T> {
+        self.device
+    }
+}
+
+impl <'a, T> Drop for U2fHidLock<'a, T> where T: HidTransport {
+    fn drop(&mut self) {
+        // the lock expires on its own if the device missed this
+        let _ = self.device.unlock();
     }
 }
 
@@ -312,7 +1056,7 @@ pub fn parse_cont_packet(data: &mut ByteBuffer, frame_size: usize) -> Result<Hid
 }
 
 pub fn parse_packet(data: &mut ByteBuffer, frame_size: usize) -> Result<HidPacket> {
-    if frame_size <= 7 {
+    if frame_size <= INIT_HEADER_SIZE {
         return Err(ErrorKind::HidPacketTooSmall.into());
     }
 
@@ -352,8 +1096,13 @@ pub fn parse_packet(data: &mut ByteBuffer, frame_size: usize) -> Result<HidPacke
 }
 
 pub fn prepare_init_packet(request: &mut ByteBuffer, channel_id: u32, command: U2fHidCommand, data: &mut ByteBuffer, packet_len: usize) {
+    prepare_raw_init_packet(request, channel_id, command as u8, data, packet_len)
+}
+
+/// Like `prepare_init_packet`, for command bytes without a `U2fHidCommand`.
+pub fn prepare_raw_init_packet(request: &mut ByteBuffer, channel_id: u32, command: u8, data: &mut ByteBuffer, packet_len: usize) {
     request.write_u32(channel_id);
-    request.write_u8(command as u8);
+    request.write_u8(command);
 
     let data_len = data.len() - data.get_rpos();
     request.write_u8(((data_len >> 8) & 0xff) as u8);
@@ -386,4 +1135,482 @@ pub fn pad_buffer(buf: &mut ByteBuffer, extra: usize) {
     for _i in 0..extra {
         buf.write_u8(0);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_multi_packet_ping_over_memory_transport() {
+        let (host, token) = MemoryTransport::pair();
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Ping, &mut buf).unwrap();
+        });
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let payload = (0..200).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut buf = ByteBuffer::from_bytes(&payload[..]);
+        device.command(U2fHidCommand::Ping, &mut buf).unwrap();
+
+        assert_eq!(buf.to_bytes(), payload);
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_cancel_pending_message() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let handle = device.cancel_handle();
+
+        let waiting = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Msg, &mut buf).unwrap();
+
+            // the user is "pressing cancel" while we wait for a touch
+            handle.cancel();
+
+            buf.clear();
+            token.recv_response(U2fHidCommand::Cancel, &mut buf).unwrap();
+
+            buf.write_u8(KEEPALIVE_CANCEL);
+            token.send_request(U2fHidCommand::Error, &mut buf).unwrap();
+        });
+
+        match device.message(&[0, 1, 0, 0]) {
+            Err(Error(ErrorKind::Cancelled, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn test_idle_cancel_ignored() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        // nothing in flight to cancel
+        device.cancel_handle().cancel();
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Ping, &mut buf).unwrap();
+        });
+
+        device.echo(&[1, 2, 3]).unwrap();
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_keepalive_reported_while_waiting() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let statuses = Arc::new(Mutex::new(vec![]));
+        let reported = statuses.clone();
+        device.set_keepalive_callback(move |status| reported.lock().unwrap().push(status));
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Msg, &mut buf).unwrap();
+
+            for status in &[KeepaliveStatus::Processing, KeepaliveStatus::UserPresenceNeeded] {
+                let mut keepalive = ByteBuffer::new();
+                keepalive.write_u8(*status as u8);
+                token.send_request(U2fHidCommand::Keepalive, &mut keepalive).unwrap();
+            }
+
+            token.send_request(U2fHidCommand::Msg, &mut buf).unwrap();
+        });
+
+        assert_eq!(device.message(&[1, 2, 3]).unwrap(), vec![1, 2, 3]);
+
+        echo.join().unwrap();
+
+        assert_eq!(*statuses.lock().unwrap(), vec![KeepaliveStatus::Processing, KeepaliveStatus::UserPresenceNeeded]);
+    }
+
+    fn write_message(transport: &MemoryTransport, channel_id: u32, command: U2fHidCommand, payload: &[u8]) {
+        let mut data = ByteBuffer::from_bytes(payload);
+        let mut report = ByteBuffer::new();
+
+        prepare_init_packet(&mut report, channel_id, command, &mut data, HID_REPORT_SIZE);
+        transport.write_report(&report.to_bytes()).unwrap();
+
+        let mut seq = 0;
+        while data.get_rpos() < data.len() {
+            report.clear();
+            prepare_cont_packet(&mut report, channel_id, seq, &mut data, HID_REPORT_SIZE);
+            transport.write_report(&report.to_bytes()).unwrap();
+            seq += 1;
+        }
+    }
+
+    fn write_cont(transport: &MemoryTransport, channel_id: u32, seq: u8, payload: &[u8]) {
+        let mut data = ByteBuffer::from_bytes(payload);
+        let mut report = ByteBuffer::new();
+
+        prepare_cont_packet(&mut report, channel_id, seq, &mut data, HID_REPORT_SIZE);
+        transport.write_report(&report.to_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_skips_foreign_channel_frames() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let payload = (0..100).map(|i| i as u8).collect::<Vec<u8>>();
+
+        // first 57 bytes of ours, then a whole message for someone else
+        let mut data = ByteBuffer::from_bytes(&payload[..]);
+        let mut report = ByteBuffer::new();
+        prepare_init_packet(&mut report, 0x01020304, U2fHidCommand::Ping, &mut data, HID_REPORT_SIZE);
+        token.write_report(&report.to_bytes()).unwrap();
+
+        write_message(&token, 0x05060708, U2fHidCommand::Ping, &payload[..]);
+        write_cont(&token, 0x01020304, 0, &payload[57..]);
+
+        let mut buf = ByteBuffer::new();
+        device.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+
+        assert_eq!(buf.to_bytes(), payload);
+    }
+
+    #[test]
+    fn test_rejects_out_of_order_continuation() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let payload = vec![7; 200];
+
+        let mut data = ByteBuffer::from_bytes(&payload[..]);
+        let mut report = ByteBuffer::new();
+        prepare_init_packet(&mut report, 0x01020304, U2fHidCommand::Ping, &mut data, HID_REPORT_SIZE);
+        token.write_report(&report.to_bytes()).unwrap();
+
+        write_cont(&token, 0x01020304, 1, &payload[57..]);
+
+        let mut buf = ByteBuffer::new();
+        match device.recv_response(U2fHidCommand::Ping, &mut buf) {
+            Err(Error(ErrorKind::InvalidMessageSequence, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_rejects_init_frame_mid_message() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let payload = vec![7; 200];
+
+        let mut data = ByteBuffer::from_bytes(&payload[..]);
+        let mut report = ByteBuffer::new();
+        prepare_init_packet(&mut report, 0x01020304, U2fHidCommand::Ping, &mut data, HID_REPORT_SIZE);
+        token.write_report(&report.to_bytes()).unwrap();
+
+        write_message(&token, 0x01020304, U2fHidCommand::Ping, &payload[..]);
+
+        let mut buf = ByteBuffer::new();
+        match device.recv_response(U2fHidCommand::Ping, &mut buf) {
+            Err(Error(ErrorKind::UnexpectedPacket, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let (host, _token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts.report = Duration::from_millis(200);
+
+        let mut buf = ByteBuffer::new();
+        match device.recv_response(U2fHidCommand::Ping, &mut buf) {
+            Err(Error(ErrorKind::Timeout, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_user_presence_deadline() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts.user_presence = Duration::from_millis(300);
+
+        let waiting = thread::spawn(move || {
+            // ask for a touch until we are cancelled
+            for _ in 0..20 {
+                write_message(&token, 0x01020304, U2fHidCommand::Keepalive, &[KeepaliveStatus::UserPresenceNeeded as u8]);
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut buf = ByteBuffer::new();
+        match device.recv_response(U2fHidCommand::Msg, &mut buf) {
+            Err(Error(ErrorKind::Timeout, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        waiting.join().unwrap();
+    }
+
+    #[test]
+    fn test_stale_reports_drained_before_command() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        // the start of a response to a request that was given up on
+        let mut data = ByteBuffer::from_bytes(&[9; 200]);
+        let mut report = ByteBuffer::new();
+        prepare_init_packet(&mut report, 0x01020304, U2fHidCommand::Ping, &mut data, HID_REPORT_SIZE);
+        token.write_report(&report.to_bytes()).unwrap();
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            token.send_request(U2fHidCommand::Ping, &mut buf).unwrap();
+        });
+
+        let mut buf = ByteBuffer::from_bytes(&[1, 2, 3]);
+        device.command(U2fHidCommand::Ping, &mut buf).unwrap();
+        assert_eq!(buf.to_bytes(), vec![1, 2, 3]);
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_resync_keeps_channel() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts.report = Duration::from_millis(500);
+
+        write_message(&token, 0x01020304, U2fHidCommand::Msg, &[0x90, 0]);
+
+        let token = thread::spawn(move || {
+            let mut report = vec![0; HID_REPORT_SIZE];
+            token.read_report(&mut report, 3000).unwrap();
+
+            let mut data = ByteBuffer::from_bytes(&report);
+            let request = parse_init_packet(&mut data, HID_REPORT_SIZE).unwrap();
+            assert_eq!((request.channel_id, request.command), (0x01020304, U2fHidCommand::Init as u8));
+
+            // an answer to an earlier INIT, then ours
+            let mut response = vec![0; 17];
+            response[8..12].copy_from_slice(&[1, 2, 3, 4]);
+            write_message(&token, 0x01020304, U2fHidCommand::Init, &response);
+
+            response[0..8].copy_from_slice(&request.payload[0..8]);
+            write_message(&token, 0x01020304, U2fHidCommand::Init, &response);
+
+            token
+        });
+
+        device.resync().unwrap();
+        assert_eq!(device.channel_id, 0x01020304);
+
+        let token = token.join().unwrap();
+
+        // a device that never answers our nonce gives up at the deadline
+        write_message(&token, 0x01020304, U2fHidCommand::Init, &[0; 17]);
+
+        match device.resync() {
+            Err(Error(ErrorKind::Timeout, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_echo_mismatch() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        let echo = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            // a flipped bit in the second packet
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Ping, &mut buf).unwrap();
+            let mut payload = buf.to_bytes();
+            payload[100] ^= 0x10;
+            token.send_request(U2fHidCommand::Ping, &mut ByteBuffer::from_bytes(&payload)).unwrap();
+        });
+
+        match device.echo(&[7; 200]) {
+            Err(Error(ErrorKind::PingMismatch(100), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn test_recovery_bounded_by_transaction_deadline() {
+        let (host, _token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.timeouts = Timeouts::with_deadline(Duration::from_millis(300));
+
+        let started = Instant::now();
+
+        match device.message(&[0, 3, 0, 0]) {
+            Err(Error(ErrorKind::Timeout, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // no INIT waiting out a whole report timeout after the deadline
+        assert!(started.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let (host, _token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.u2f_info = Some(U2fHidDeviceInfo {
+            protocol_version: 2,
+            major_device_version: 0,
+            minor_device_version: 0,
+            build_device_version: 0,
+            raw_capabilities: CAPABILITY_CBOR | CAPABILITY_NMSG,
+        });
+
+        assert!(device.supports_cbor());
+
+        // neither reaches the device, which would never answer
+        device.wink().unwrap();
+
+        match device.message(&[0, 3, 0, 0]) {
+            Err(Error(ErrorKind::UnsupportedCommand(U2fHidCommand::Msg), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_lock_needs_capability() {
+        let (host, _token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+        device.u2f_info = Some(U2fHidDeviceInfo {
+            protocol_version: 2,
+            major_device_version: 0,
+            minor_device_version: 0,
+            build_device_version: 0,
+            raw_capabilities: 0,
+        });
+
+        assert!(!device.capabilities().unwrap().supports(U2fHidCommand::Lock));
+
+        // refused without asking the device, which would never answer
+        match device.lock(Duration::from_secs(5)).map(|_| ()) {
+            Err(Error(ErrorKind::UnsupportedCommand(U2fHidCommand::Lock), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_vendor_command() {
+        let (host, token) = MemoryTransport::pair();
+
+        let echo = thread::spawn(move || {
+            let mut report = vec![0; HID_REPORT_SIZE];
+            token.read_report(&mut report, 3000).unwrap();
+
+            let mut data = ByteBuffer::from_bytes(&report);
+            let request = parse_init_packet(&mut data, HID_REPORT_SIZE).unwrap();
+            assert_eq!(request.command, 0xc1);
+
+            let mut payload = ByteBuffer::from_bytes(&request.payload[0..request.len]);
+            let mut response = ByteBuffer::new();
+            prepare_raw_init_packet(&mut response, request.channel_id, 0xc1, &mut payload, HID_REPORT_SIZE);
+            token.write_report(&response.to_bytes()).unwrap();
+        });
+
+        let mut device = U2fHidDevice::new(host);
+        device.channel_id = 0x01020304;
+
+        assert_eq!(device.vendor_command(0xc1, &[1, 2, 3]).unwrap(), vec![1, 2, 3]);
+
+        echo.join().unwrap();
+
+        match device.vendor_command(U2fHidCommand::Msg as u8, &[]) {
+            Err(Error(ErrorKind::InvalidVendorCommand(0x83), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_packet_size_bounds() {
+        let (host, _token) = MemoryTransport::pair();
+        let host = Arc::new(host);
+
+        for &size in [0, INIT_HEADER_SIZE].iter() {
+            match U2fHidDevice::with_packet_size(host.clone(), size) {
+                Err(Error(ErrorKind::HidPacketTooSmall, _)) => {},
+                other => panic!("unexpected result for {}: {:?}", size, other.map(|_| ())),
+            }
+        }
+
+        match U2fHidDevice::with_packet_size(host.clone(), HID_REPORT_SIZE + 1) {
+            Err(Error(ErrorKind::HidPacketTooLarge, _)) => {},
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        assert!(U2fHidDevice::with_packet_size(host.clone(), INIT_HEADER_SIZE + 1).is_ok());
+        assert!(U2fHidDevice::with_packet_size(host, HID_REPORT_SIZE).is_ok());
+
+        // messages never outgrow the BCNT field, whatever the packet size
+        assert_eq!(max_message_len(HID_REPORT_SIZE), 7609);
+        assert_eq!(max_message_len(1024), 0xffff);
+    }
+}
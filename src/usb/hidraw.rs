@@ -0,0 +1,313 @@
+//! Linux hidraw backend that talks to `/dev/hidraw*` directly, without
+//! hidapi or libusb.
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use libc;
+
+use super::error::*;
+use super::descriptor::*;
+use super::hid::*;
+use super::transport::*;
+use super::FidoExt;
+
+pub const SYSFS_HIDRAW_ROOT: &'static str = "/sys/class/hidraw";
+pub const DEV_ROOT: &'static str = "/dev";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HidrawDeviceInfo {
+    pub path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub serial_number: Option<String>,
+    pub manufacturer_string: Option<String>,
+    pub product_string: Option<String>,
+    pub usage_page: u16,
+    pub usage: u16,
+    pub input_report_size: usize,
+    pub output_report_size: usize,
+}
+
+impl HidrawDeviceInfo {
+    pub fn collection(&self) -> HidCollection {
+        HidCollection {
+            usage_page: self.usage_page,
+            usage: self.usage,
+            input_report_size: self.input_report_size,
+            output_report_size: self.output_report_size,
+        }
+    }
+}
+
+/// Enumerates hidraw nodes through sysfs.
+///
+/// The roots are configurable so enumeration can run against a fake sysfs
+/// tree; each node is expected at `<sysfs_root>/hidrawN/device/` with
+/// `uevent` and `report_descriptor` files, and opened as `<dev_root>/hidrawN`.
+/// For USB devices the `manufacturer` string is read from the USB device,
+/// two levels up from `device/`.
+pub struct Hidraw {
+    sysfs_root: PathBuf,
+    dev_root: PathBuf,
+}
+
+impl Hidraw {
+    pub fn new() -> Hidraw {
+        Self::with_roots(SYSFS_HIDRAW_ROOT, DEV_ROOT)
+    }
+
+    pub fn with_roots<P: AsRef<Path>, Q: AsRef<Path>>(sysfs_root: P, dev_root: Q) -> Hidraw {
+        Hidraw {
+            sysfs_root: sysfs_root.as_ref().to_owned(),
+            dev_root: dev_root.as_ref().to_owned(),
+        }
+    }
+
+    /// Lists every hidraw node, one entry per top-level collection.
+    pub fn devices(&self) -> Result<Vec<HidrawDeviceInfo>> {
+        let mut devices = vec![];
+
+        let mut names = vec![];
+        for entry in fs::read_dir(&self.sysfs_root)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("hidraw") {
+                names.push(name);
+            }
+        }
+        names.sort();
+
+        for name in names {
+            match self.device_info(&name) {
+                Ok(infos) => devices.extend(infos),
+                // not every hidraw node belongs to a USB device
+                Err(_) => {},
+            }
+        }
+
+        Ok(devices)
+    }
+
+    fn device_info(&self, name: &str) -> Result<Vec<HidrawDeviceInfo>> {
+        let device_dir = self.sysfs_root.join(name).join("device");
+
+        let mut uevent = String::new();
+        File::open(device_dir.join("uevent"))?.read_to_string(&mut uevent)?;
+
+        let mut report_descriptor = vec![];
+        File::open(device_dir.join("report_descriptor"))?.read_to_end(&mut report_descriptor)?;
+
+        let mut vendor_id = 0;
+        let mut product_id = 0;
+        let mut serial_number = None;
+        let mut product_string = None;
+
+        for line in uevent.lines() {
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts.next().unwrap_or("");
+
+            match key {
+                "HID_ID" => {
+                    // bus:vendor:product in hex
+                    let ids = value.split(':').collect::<Vec<&str>>();
+                    if ids.len() == 3 {
+                        vendor_id = u32::from_str_radix(ids[1], 16).unwrap_or(0) as u16;
+                        product_id = u32::from_str_radix(ids[2], 16).unwrap_or(0) as u16;
+                    }
+                },
+                "HID_NAME" => product_string = Some(value.to_owned()),
+                "HID_UNIQ" if !value.is_empty() => serial_number = Some(value.to_owned()),
+                _ => {}
+            }
+        }
+
+        // device/ is the HID device, below the USB interface and then the
+        // USB device; other buses have no manufacturer string
+        let mut manufacturer_string = None;
+        if let Ok(mut file) = File::open(device_dir.join("..").join("..").join("manufacturer")) {
+            let mut manufacturer = String::new();
+            if file.read_to_string(&mut manufacturer).is_ok() && !manufacturer.trim().is_empty() {
+                manufacturer_string = Some(manufacturer.trim().to_owned());
+            }
+        }
+
+        let descriptor = ReportDescriptor::parse(&report_descriptor)?;
+        let path = self.dev_root.join(name).to_string_lossy().into_owned();
+
+        Ok(descriptor.collections.iter().map(|collection| {
+            HidrawDeviceInfo {
+                path: path.clone(),
+                vendor_id: vendor_id,
+                product_id: product_id,
+                serial_number: serial_number.clone(),
+                manufacturer_string: manufacturer_string.clone(),
+                product_string: product_string.clone(),
+                usage_page: collection.usage_page,
+                usage: collection.usage,
+                input_report_size: collection.input_report_size,
+                output_report_size: collection.output_report_size,
+            }
+        }).collect())
+    }
+
+    pub fn open(&self, device: &HidrawDeviceInfo) -> Result<HidrawDevice> {
+        HidrawDevice::open(&device.path)
+    }
+
+    /// Opens `device` as a U2FHID device using the report size from its
+    /// report descriptor.
+    pub fn open_u2f(&self, device: &HidrawDeviceInfo) -> Result<U2fHidDevice<HidrawDevice>> {
+        U2fHidDevice::with_packet_size(self.open(device)?, device.collection().packet_size()?)
+    }
+
+    /// U2FHID packet size of the FIDO collection of the node at `path`,
+    /// e.g. a `/dev/hidrawN` path reported by hidapi.
+    pub fn packet_size(&self, path: &str) -> Result<usize> {
+        let name = match Path::new(path).file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => bail!(ErrorKind::InvalidReportDescriptor),
+        };
+
+        if !name.starts_with("hidraw") {
+            bail!(ErrorKind::InvalidReportDescriptor);
+        }
+
+        match self.device_info(&name)?.iter().find(|device| device.usage_page == FIDO_USAGE_PAGE && device.usage == U2F_USAGE) {
+            Some(device) => device.collection().packet_size(),
+            None => bail!(ErrorKind::InvalidReportDescriptor),
+        }
+    }
+}
+
+impl FidoExt for Hidraw {
+    type DeviceInfo = HidrawDeviceInfo;
+
+    fn fido_devices(&self) -> Vec<HidrawDeviceInfo> {
+        self.devices()
+            .unwrap_or(vec![])
+            .into_iter()
+            .filter(|device| device.usage_page == FIDO_USAGE_PAGE && device.usage == U2F_USAGE)
+            .collect::<Vec<HidrawDeviceInfo>>()
+    }
+}
+
+/// An open `/dev/hidrawN` node.
+pub struct HidrawDevice {
+    file: File,
+}
+
+impl HidrawDevice {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<HidrawDevice> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+
+        Ok(HidrawDevice {
+            file: file,
+        })
+    }
+}
+
+impl HidTransport for HidrawDevice {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let mut buf = Vec::with_capacity(report.len() + 1);
+        buf.push(0x0); // hid report number
+        buf.extend_from_slice(report);
+
+        Ok((&self.file).write(&buf[..])?)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let mut fds = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let ready = unsafe { libc::poll(&mut fds, 1, timeout_millis) };
+
+        if ready < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        if ready == 0 {
+            return Ok(0);
+        }
+
+        Ok((&self.file).read(report)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+    use rand;
+
+    const FIDO_REPORT_DESCRIPTOR: &'static [u8] = &[
+        0x06, 0xd0, 0xf1, 0x09, 0x01, 0xa1, 0x01,
+        0x09, 0x20, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x40, 0x81, 0x02,
+        0x09, 0x21, 0x15, 0x00, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x40, 0x91, 0x02,
+        0xc0,
+    ];
+
+    const KEYBOARD_REPORT_DESCRIPTOR: &'static [u8] = &[
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01,
+        0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02,
+        0xc0,
+    ];
+
+    /// Lays out a hidraw node as sysfs does for USB: `hidrawN/device` links
+    /// to the HID device, below an interface and a device directory.
+    fn fake_node(root: &Path, name: &str, uevent: &str, descriptor: &[u8]) {
+        let usb_dir = root.join("devices").join(name);
+        let device_dir = usb_dir.join("1-1:1.0").join("0003:1050:0407.0001");
+        fs::create_dir_all(&device_dir).unwrap();
+        fs::create_dir_all(root.join(name)).unwrap();
+        ::std::os::unix::fs::symlink(&device_dir, root.join(name).join("device")).unwrap();
+
+        File::create(device_dir.join("uevent")).unwrap().write_all(uevent.as_bytes()).unwrap();
+        File::create(device_dir.join("report_descriptor")).unwrap().write_all(descriptor).unwrap();
+    }
+
+    #[test]
+    fn test_fido_devices_from_fake_sysfs() {
+        let root = env::temp_dir().join(format!("u2f-hidraw-test-{}", rand::random::<u32>()));
+
+        fake_node(&root, "hidraw0",
+            "DRIVER=hid-generic\nHID_ID=0003:0000046D:0000C31C\nHID_NAME=Logitech Keyboard\nHID_UNIQ=\n",
+            KEYBOARD_REPORT_DESCRIPTOR);
+        fake_node(&root, "hidraw1",
+            "DRIVER=hid-generic\nHID_ID=0003:00001050:00000407\nHID_NAME=Yubico YubiKey\nHID_UNIQ=12345\n",
+            FIDO_REPORT_DESCRIPTOR);
+        File::create(root.join("devices").join("hidraw1").join("manufacturer")).unwrap().write_all(b"Yubico\n").unwrap();
+
+        let hidraw = Hidraw::with_roots(&root, "/dev");
+
+        assert_eq!(hidraw.devices().unwrap().len(), 2);
+
+        let devices = hidraw.fido_devices();
+
+        // as for a path reported by hidapi's hidraw backend
+        assert_eq!(hidraw.packet_size("/dev/hidraw1").unwrap(), 64);
+        assert!(hidraw.packet_size("/dev/hidraw0").is_err());
+        assert!(hidraw.packet_size("0001:0004:00").is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(devices, vec![HidrawDeviceInfo {
+            path: "/dev/hidraw1".to_owned(),
+            vendor_id: 0x1050,
+            product_id: 0x0407,
+            serial_number: Some("12345".to_owned()),
+            manufacturer_string: Some("Yubico".to_owned()),
+            product_string: Some("Yubico YubiKey".to_owned()),
+            usage_page: FIDO_USAGE_PAGE,
+            usage: U2F_USAGE,
+            input_report_size: 64,
+            output_report_size: 64,
+        }]);
+    }
+}
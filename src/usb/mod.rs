@@ -1,18 +1,39 @@
+#[cfg(feature = "hidapi")]
 use hidapi::*;
+#[cfg(feature = "hidapi")]
 use self::hid::*;
 
 pub mod error;
 pub mod hid;
+pub mod transport;
+pub mod descriptor;
+pub mod watcher;
+pub mod record;
+pub mod pcap;
+pub mod mux;
+pub mod filter;
+#[cfg(target_os = "linux")]
+pub mod hidraw;
 
 pub trait FidoExt {
-    fn fido_devices(&self) -> Vec<HidDeviceInfo>;
+    type DeviceInfo;
+
+    fn fido_devices(&self) -> Vec<Self::DeviceInfo>;
+
+    /// The FIDO devices that `filter` matches.
+    fn matching_fido_devices(&self, filter: &filter::DeviceFilter) -> Vec<Self::DeviceInfo> where Self::DeviceInfo: filter::DeviceIdentity {
+        filter.filter(self.fido_devices())
+    }
 }
 
+#[cfg(feature = "hidapi")]
 impl FidoExt for HidApi {
+    type DeviceInfo = HidDeviceInfo;
+
     fn fido_devices(&self) -> Vec<HidDeviceInfo> {
         self.devices()
             .into_iter()
             .filter(|device| device.usage_page == FIDO_USAGE_PAGE && device.usage == U2F_USAGE)
             .collect::<Vec<HidDeviceInfo>>()
     }
-}
\ No newline at end of file
+}
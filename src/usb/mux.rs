@@ -0,0 +1,300 @@
+//! Several U2FHID channels on one device.
+//!
+//! `ChannelMux` owns the device's transport and runs a thread that reads
+//! every report and hands it to the channel it is addressed to. Each
+//! channel is a `U2fHidDevice` of its own, initialised with its own channel
+//! id, so commands on different channels can be in flight at once.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::cmp;
+
+use super::error::*;
+use super::hid::*;
+use super::transport::*;
+
+// each route is the id of a ChannelTransport and where its reports go
+struct Routes {
+    // routes by the channel id a transport last sent on
+    channels: HashMap<u32, (usize, Sender<Vec<u8>>)>,
+    // transports in the middle of INIT, which all see broadcast reports
+    broadcast: Vec<(usize, Sender<Vec<u8>>)>,
+}
+
+struct Shared<T> {
+    transport: T,
+    routes: Mutex<Routes>,
+    stopped: AtomicBool,
+    disconnected: AtomicBool,
+    next_id: AtomicUsize,
+}
+
+/// Shares one device between any number of channels.
+///
+/// The transport is read from the mux's thread while channels write to it
+/// from theirs, hence the `Sync` bound.
+pub struct ChannelMux<T> where T: HidTransport + Send + Sync + 'static {
+    shared: Arc<Shared<T>>,
+    packet_size: usize,
+    reader: Option<JoinHandle<()>>,
+}
+
+impl <T> ChannelMux<T> where T: HidTransport + Send + Sync + 'static {
+    pub fn new(transport: T) -> ChannelMux<T> {
+        Self::with_packet_size(transport, HID_REPORT_SIZE)
+    }
+
+    pub fn with_packet_size(transport: T, packet_size: usize) -> ChannelMux<T> {
+        let shared = Arc::new(Shared {
+            transport: transport,
+            routes: Mutex::new(Routes {
+                channels: HashMap::new(),
+                broadcast: vec![],
+            }),
+            stopped: AtomicBool::new(false),
+            disconnected: AtomicBool::new(false),
+            next_id: AtomicUsize::new(0),
+        });
+
+        let reader_shared = shared.clone();
+        let reader = thread::spawn(move || Self::read_reports(reader_shared, packet_size));
+
+        ChannelMux {
+            shared: shared,
+            packet_size: packet_size,
+            reader: Some(reader),
+        }
+    }
+
+    /// Allocates a new channel on the device.
+    pub fn open_channel(&self) -> Result<U2fHidDevice<ChannelTransport<T>>> {
+        let (sender, receiver) = channel();
+        let id = self.shared.next_id.fetch_add(1, Ordering::SeqCst);
+
+        let transport = ChannelTransport {
+            shared: self.shared.clone(),
+            id: id,
+            sender: sender,
+            receiver: Mutex::new(receiver),
+        };
+
+        let mut device = U2fHidDevice::with_packet_size(transport, self.packet_size)?;
+        device.init()?;
+
+        // claim the allocated channel and stop seeing other channels' INIT
+        // responses
+        let mut routes = self.shared.routes.lock().expect("mux routes lock");
+        routes.broadcast.retain(|&(other, _)| other != id);
+        routes.channels.insert(device.channel_id, (id, device.hid_device.sender.clone()));
+
+        Ok(device)
+    }
+
+    fn read_reports(shared: Arc<Shared<T>>, packet_size: usize) {
+        let mut report = vec![0; packet_size];
+
+        while !shared.stopped.load(Ordering::SeqCst) {
+            let bytes = match shared.transport.read_report(&mut report, CANCEL_POLL_MILLIS) {
+                Ok(bytes) => bytes,
+                // disconnects the channels
+                Err(_) => break,
+            };
+
+            if bytes < 4 {
+                continue;
+            }
+
+            let channel_id = ((report[0] as u32) << 24) | ((report[1] as u32) << 16) | ((report[2] as u32) << 8) | report[3] as u32;
+            let data = report[0..bytes].to_owned();
+
+            let routes = shared.routes.lock().expect("mux routes lock");
+
+            if channel_id == BROADCAST_CID {
+                for &(_, ref sender) in routes.broadcast.iter() {
+                    let _ = sender.send(data.clone());
+                }
+            } else if let Some(&(_, ref sender)) = routes.channels.get(&channel_id) {
+                let _ = sender.send(data);
+            }
+        }
+
+        shared.disconnected.store(true, Ordering::SeqCst);
+    }
+}
+
+impl <T> Drop for ChannelMux<T> where T: HidTransport + Send + Sync + 'static {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::SeqCst);
+
+        if let Some(reader) = self.reader.take() {
+            let _ = reader.join();
+        }
+    }
+}
+
+/// One channel's view of a `ChannelMux` device.
+pub struct ChannelTransport<T> {
+    shared: Arc<Shared<T>>,
+    id: usize,
+    sender: Sender<Vec<u8>>,
+    receiver: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl <T> HidTransport for ChannelTransport<T> where T: HidTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        if report.len() >= 4 {
+            let channel_id = ((report[0] as u32) << 24) | ((report[1] as u32) << 16) | ((report[2] as u32) << 8) | report[3] as u32;
+
+            // the response arrives on the channel we send on, so route it
+            // to us before it can arrive
+            let mut routes = self.shared.routes.lock().expect("mux routes lock");
+            let id = self.id;
+
+            routes.broadcast.retain(|&(other, _)| other != id);
+
+            if channel_id == BROADCAST_CID {
+                routes.broadcast.push((id, self.sender.clone()));
+            } else {
+                routes.channels.insert(channel_id, (id, self.sender.clone()));
+            }
+        }
+
+        if self.shared.disconnected.load(Ordering::SeqCst) {
+            bail!(ErrorKind::TransportDisconnected);
+        }
+
+        self.shared.transport.write_report(report)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let receiver = self.receiver.lock().expect("channel receiver lock");
+        let mut remaining = timeout_millis;
+
+        // wake up now and then to notice the reader thread giving up, which
+        // only matters once reports it already passed on have been read
+        loop {
+            let wait = if remaining < 0 { CANCEL_POLL_MILLIS } else { cmp::min(remaining, CANCEL_POLL_MILLIS) };
+
+            match receiver.recv_timeout(Duration::from_millis(wait as u64)) {
+                Ok(data) => {
+                    let len = cmp::min(data.len(), report.len());
+                    report[0..len].copy_from_slice(&data[0..len]);
+                    return Ok(len);
+                },
+                Err(RecvTimeoutError::Timeout) => {
+                    if self.shared.disconnected.load(Ordering::SeqCst) {
+                        bail!(ErrorKind::TransportDisconnected);
+                    }
+
+                    if remaining >= 0 {
+                        remaining -= wait;
+
+                        if remaining <= 0 {
+                            return Ok(0);
+                        }
+                    }
+                },
+                Err(RecvTimeoutError::Disconnected) => bail!(ErrorKind::TransportDisconnected),
+            }
+        }
+    }
+}
+
+impl <T> Drop for ChannelTransport<T> {
+    fn drop(&mut self) {
+        let mut routes = self.shared.routes.lock().expect("mux routes lock");
+        let id = self.id;
+
+        routes.broadcast.retain(|&(other, _)| other != id);
+
+        let channel_ids = routes.channels.iter()
+            .filter(|&(_, &(other, _))| other == id)
+            .map(|(channel_id, _)| *channel_id)
+            .collect::<Vec<u32>>();
+
+        for channel_id in channel_ids {
+            routes.channels.remove(&channel_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use std::time::Instant;
+    use ::{with_user_presence, U2fDevice, U2fVersion};
+
+    #[test]
+    fn test_authenticate_while_polling_other_channel() {
+        let soft = Arc::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+        let mux = ChannelMux::new(soft.clone());
+
+        let signer = mux.open_channel().unwrap();
+        let poller = mux.open_channel().unwrap();
+        assert!(signer.channel_id != poller.channel_id);
+
+        let challenge = vec![1; 32];
+        let application = vec![2; 32];
+        let key_handle = signer.register(&challenge, &application).unwrap().key_handle;
+
+        soft.authenticator().set_auto_presence(false);
+
+        let authenticate = thread::spawn(move || {
+            with_user_presence(Duration::from_secs(10), || signer.authenticate(&challenge, &application, &key_handle))
+        });
+
+        // the other channel keeps working while the first waits for a touch
+        let started = Instant::now();
+        while started.elapsed() < Duration::from_millis(500) {
+            assert_eq!(poller.get_version().unwrap(), U2fVersion::V2);
+            poller.ping().unwrap();
+        }
+
+        soft.authenticator().touch();
+
+        assert_eq!(authenticate.join().unwrap().unwrap().counter, 1);
+    }
+
+    #[test]
+    fn test_channels_see_only_their_reports() {
+        let (device_end, host_end) = MemoryTransport::pair();
+        let mux = ChannelMux::new(host_end);
+
+        let responder = thread::spawn(move || {
+            let mut report = vec![0; HID_REPORT_SIZE];
+
+            // answer each INIT with a new channel, then echo a ping on channel 2
+            for channel_id in 1..3u32 {
+                device_end.read_report(&mut report, -1).unwrap();
+                let mut response = report.clone();
+                response[4..7].copy_from_slice(&[U2fHidCommand::Init as u8, 0, 17]);
+                response[15..19].copy_from_slice(&[0, 0, 0, channel_id as u8]);
+                device_end.write_report(&response).unwrap();
+            }
+
+            device_end.read_report(&mut report, -1).unwrap();
+            let mut stray = report.clone();
+            stray[0..4].copy_from_slice(&[0, 0, 0, 1]);
+            device_end.write_report(&stray).unwrap();
+            device_end.write_report(&report).unwrap();
+        });
+
+        let first = mux.open_channel().unwrap();
+        let second = mux.open_channel().unwrap();
+        assert_eq!((first.channel_id, second.channel_id), (1, 2));
+
+        second.ping().unwrap();
+        responder.join().unwrap();
+
+        // the echo sent to channel 1 is waiting for it, not consumed by channel 2
+        let mut report = vec![0; HID_REPORT_SIZE];
+        assert_eq!(first.hid_device.read_report(&mut report, 1000).unwrap(), HID_REPORT_SIZE);
+        assert_eq!(&report[0..5], &[0, 0, 0, 1, U2fHidCommand::Ping as u8]);
+    }
+}
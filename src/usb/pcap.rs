@@ -0,0 +1,531 @@
+//! Reading U2FHID reports out of USB packet captures.
+//!
+//! Understands pcap and pcapng files holding Linux usbmon captures (as
+//! written by Wireshark or tcpdump on `usbmonN`) and USBPcap captures from
+//! Windows. The interrupt transfers are turned into `RecordedReport`s, which
+//! can be fed to `dissect::transactions` or replayed.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::error::*;
+use super::record::{Direction, RecordedReport};
+
+pub const LINKTYPE_USB_LINUX: u32 = 189;
+pub const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+pub const LINKTYPE_USBPCAP: u32 = 249;
+
+const PCAP_MAGIC_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NANOS: u32 = 0xa1b23c4d;
+const PCAPNG_SECTION_HEADER: u32 = 0x0a0d0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_SIMPLE_PACKET: u32 = 3;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPTION_TSRESOL: u16 = 9;
+
+const USB_TRANSFER_INTERRUPT: u8 = 1;
+const USB_DIR_IN: u8 = 0x80;
+
+/// A report together with the USB device it was exchanged with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedReport {
+    pub bus: u16,
+    pub device: u16,
+    pub report: RecordedReport,
+}
+
+struct Frame<'a> {
+    timestamp_micros: u64,
+    link_type: u32,
+    big_endian: bool,
+    data: &'a [u8],
+}
+
+pub fn open_capture<P: AsRef<Path>>(path: P) -> Result<Vec<CapturedReport>> {
+    let mut data = vec![];
+    File::open(path)?.read_to_end(&mut data)?;
+
+    parse_capture(&data)
+}
+
+/// Extracts the interrupt transfer reports from a pcap or pcapng capture.
+/// Reports of every device in the capture are returned, in capture order;
+/// filter on `bus` and `device` to follow one token.
+pub fn parse_capture(data: &[u8]) -> Result<Vec<CapturedReport>> {
+    let frames = match read_u32(data, 0, false) {
+        Some(PCAPNG_SECTION_HEADER) => parse_pcapng(data)?,
+        Some(_) => parse_pcap(data)?,
+        None => bail!(ErrorKind::InvalidCapture("file too short".to_owned())),
+    };
+
+    let mut reports = vec![];
+    let mut started = None;
+
+    for frame in frames.iter() {
+        let transfer = match frame.link_type {
+            LINKTYPE_USB_LINUX => usbmon_transfer(frame, 48)?,
+            LINKTYPE_USB_LINUX_MMAPPED => usbmon_transfer(frame, 64)?,
+            LINKTYPE_USBPCAP => usbpcap_transfer(frame)?,
+            link_type => bail!(ErrorKind::UnsupportedLinkType(link_type)),
+        };
+
+        if let Some((bus, device, direction, data)) = transfer {
+            let started = *started.get_or_insert(frame.timestamp_micros);
+
+            reports.push(CapturedReport {
+                bus: bus,
+                device: device,
+                report: RecordedReport {
+                    elapsed_millis: frame.timestamp_micros.saturating_sub(started) / 1000,
+                    direction: direction,
+                    data: data.to_owned(),
+                },
+            });
+        }
+    }
+
+    Ok(reports)
+}
+
+fn parse_pcap(data: &[u8]) -> Result<Vec<Frame>> {
+    let (big_endian, nanos) = match read_u32(data, 0, false) {
+        Some(PCAP_MAGIC_MICROS) => (false, false),
+        Some(PCAP_MAGIC_NANOS) => (false, true),
+        _ => match read_u32(data, 0, true) {
+            Some(PCAP_MAGIC_MICROS) => (true, false),
+            Some(PCAP_MAGIC_NANOS) => (true, true),
+            _ => bail!(ErrorKind::InvalidCapture("not a pcap or pcapng file".to_owned())),
+        },
+    };
+
+    let link_type = read_u32(data, 20, big_endian)
+        .ok_or_else(|| ErrorKind::InvalidCapture("truncated pcap header".to_owned()))?;
+
+    let mut frames = vec![];
+    let mut offset = 24;
+
+    while offset < data.len() {
+        let (seconds, fraction, captured) = match (read_u32(data, offset, big_endian), read_u32(data, offset + 4, big_endian), read_u32(data, offset + 8, big_endian)) {
+            (Some(seconds), Some(fraction), Some(captured)) => (seconds as u64, fraction as u64, captured as usize),
+            _ => bail!(ErrorKind::InvalidCapture(format!("truncated record at {}", offset))),
+        };
+
+        let start = offset + 16;
+
+        if start + captured > data.len() {
+            bail!(ErrorKind::InvalidCapture(format!("truncated record at {}", offset)));
+        }
+
+        frames.push(Frame {
+            timestamp_micros: seconds * 1000000 + if nanos { fraction / 1000 } else { fraction },
+            link_type: link_type,
+            big_endian: big_endian,
+            data: &data[start..start + captured],
+        });
+
+        offset = start + captured;
+    }
+
+    Ok(frames)
+}
+
+fn parse_pcapng(data: &[u8]) -> Result<Vec<Frame>> {
+    let mut frames = vec![];
+    let mut big_endian = false;
+    // (link type, timestamp units per second) of each interface in the section
+    let mut interfaces: Vec<(u32, u64)> = vec![];
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let block_type = read_u32(data, offset, big_endian);
+
+        if block_type == Some(PCAPNG_SECTION_HEADER) {
+            big_endian = match read_u32(data, offset + 8, false) {
+                Some(PCAPNG_BYTE_ORDER_MAGIC) => false,
+                _ if read_u32(data, offset + 8, true) == Some(PCAPNG_BYTE_ORDER_MAGIC) => true,
+                _ => bail!(ErrorKind::InvalidCapture(format!("bad byte order magic at {}", offset))),
+            };
+
+            interfaces.clear();
+        }
+
+        let len = match read_u32(data, offset + 4, big_endian) {
+            Some(len) if len >= 12 && len % 4 == 0 && offset + len as usize <= data.len() => len as usize,
+            _ => bail!(ErrorKind::InvalidCapture(format!("bad block length at {}", offset))),
+        };
+
+        let body = &data[offset + 8..offset + len - 4];
+        let truncated = || ErrorKind::InvalidCapture(format!("truncated block at {}", offset));
+
+        match block_type {
+            Some(PCAPNG_INTERFACE_DESCRIPTION) => {
+                let link_type = read_u16(body, 0, big_endian).ok_or_else(&truncated)? as u32;
+                let resolution = pcapng_ts_resolution(&body[::std::cmp::min(8, body.len())..], big_endian)
+                    .ok_or_else(|| ErrorKind::InvalidCapture(format!("timestamp resolution out of range at {}", offset)))?;
+
+                interfaces.push((link_type, resolution));
+            },
+            Some(PCAPNG_ENHANCED_PACKET) => {
+                let (interface, high, low, captured) = match (read_u32(body, 0, big_endian), read_u32(body, 4, big_endian), read_u32(body, 8, big_endian), read_u32(body, 12, big_endian)) {
+                    (Some(interface), Some(high), Some(low), Some(captured)) => (interface as usize, high as u64, low as u64, captured as usize),
+                    _ => bail!(truncated()),
+                };
+
+                let &(link_type, resolution) = interfaces.get(interface)
+                    .ok_or_else(|| ErrorKind::InvalidCapture(format!("unknown interface {} at {}", interface, offset)))?;
+
+                if 20 + captured > body.len() {
+                    bail!(truncated());
+                }
+
+                let timestamp = pcapng_micros((high << 32) | low, resolution)
+                    .ok_or_else(|| ErrorKind::InvalidCapture(format!("timestamp out of range at {}", offset)))?;
+
+                frames.push(Frame {
+                    timestamp_micros: timestamp,
+                    link_type: link_type,
+                    big_endian: big_endian,
+                    data: &body[20..20 + captured],
+                });
+            },
+            Some(PCAPNG_SIMPLE_PACKET) => {
+                let &(link_type, _) = interfaces.first()
+                    .ok_or_else(|| ErrorKind::InvalidCapture(format!("packet before interface at {}", offset)))?;
+
+                let original = read_u32(body, 0, big_endian).ok_or_else(&truncated)? as usize;
+                let captured = ::std::cmp::min(original, body.len() - 4);
+
+                // simple packets have no timestamp
+                frames.push(Frame {
+                    timestamp_micros: 0,
+                    link_type: link_type,
+                    big_endian: big_endian,
+                    data: &body[4..4 + captured],
+                });
+            },
+            _ => {},
+        }
+
+        offset += len;
+    }
+
+    Ok(frames)
+}
+
+/// Timestamp units per second from an interface's `if_tsresol` option,
+/// microseconds by default, or `None` if they do not fit in a `u64`.
+fn pcapng_ts_resolution(options: &[u8], big_endian: bool) -> Option<u64> {
+    let mut offset = 0;
+
+    while let (Some(code), Some(len)) = (read_u16(options, offset, big_endian), read_u16(options, offset + 2, big_endian)) {
+        if code == 0 {
+            break;
+        }
+
+        if code == PCAPNG_OPTION_TSRESOL && len == 1 && offset + 4 < options.len() {
+            let tsresol = options[offset + 4];
+            let (base, exponent) = if tsresol & 0x80 == 0 { (10u64, tsresol) } else { (2u64, tsresol & 0x7f) };
+
+            let mut resolution = 1u64;
+
+            for _ in 0..exponent {
+                resolution = match resolution.checked_mul(base) {
+                    Some(resolution) => resolution,
+                    None => return None,
+                };
+            }
+
+            return Some(resolution);
+        }
+
+        offset += 4 + (len as usize + 3) / 4 * 4;
+    }
+
+    Some(1000000)
+}
+
+/// Converts a pcapng timestamp in units of `1 / resolution` seconds to
+/// microseconds, or `None` if that does not fit in a `u64`.
+fn pcapng_micros(timestamp: u64, resolution: u64) -> Option<u64> {
+    let seconds = match (timestamp / resolution).checked_mul(1000000) {
+        Some(seconds) => seconds,
+        None => return None,
+    };
+
+    let fraction = timestamp % resolution;
+
+    let micros = match fraction.checked_mul(1000000) {
+        Some(fraction) => fraction / resolution,
+        // only for resolutions finer than 2^44 per second, which are at
+        // least a million times finer than a microsecond
+        None => fraction / (resolution / 1000000),
+    };
+
+    seconds.checked_add(micros)
+}
+
+/// The data of a usbmon interrupt transfer: OUT data is carried by the
+/// submission, IN data by the completion.
+fn usbmon_transfer<'a>(frame: &Frame<'a>, header_len: usize) -> Result<Option<(u16, u16, Direction, &'a [u8])>> {
+    let data = frame.data;
+
+    if data.len() < header_len {
+        bail!(ErrorKind::InvalidCapture("truncated usbmon header".to_owned()));
+    }
+
+    let event = data[8];
+    let transfer_type = data[9];
+    let endpoint = data[10];
+    let device = data[11] as u16;
+    let bus = read_u16(data, 12, frame.big_endian).unwrap_or(0);
+    let captured = read_u32(data, 36, frame.big_endian).unwrap_or(0) as usize;
+
+    let direction = match (event, endpoint & USB_DIR_IN) {
+        (b'S', 0) => Direction::Write,
+        (b'C', USB_DIR_IN) => Direction::Read,
+        _ => return Ok(None),
+    };
+
+    if transfer_type != USB_TRANSFER_INTERRUPT || captured == 0 {
+        return Ok(None);
+    }
+
+    let end = ::std::cmp::min(header_len + captured, data.len());
+
+    Ok(Some((bus, device, direction, &data[header_len..end])))
+}
+
+fn usbpcap_transfer<'a>(frame: &Frame<'a>) -> Result<Option<(u16, u16, Direction, &'a [u8])>> {
+    let data = frame.data;
+
+    // USBPcap headers are always little endian
+    let header_len = match read_u16(data, 0, false) {
+        Some(len) if len >= 27 && len as usize <= data.len() => len as usize,
+        _ => bail!(ErrorKind::InvalidCapture("truncated USBPcap header".to_owned())),
+    };
+
+    let completion = data[16] & 1 != 0;
+    let bus = read_u16(data, 17, false).unwrap_or(0);
+    let device = read_u16(data, 19, false).unwrap_or(0);
+    let endpoint = data[21];
+    let transfer_type = data[22];
+
+    let direction = match (completion, endpoint & USB_DIR_IN) {
+        (false, 0) => Direction::Write,
+        (true, USB_DIR_IN) => Direction::Read,
+        _ => return Ok(None),
+    };
+
+    if transfer_type != USB_TRANSFER_INTERRUPT || data.len() == header_len {
+        return Ok(None);
+    }
+
+    Ok(Some((bus, device, direction, &data[header_len..])))
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    if offset + 2 > data.len() {
+        return None;
+    }
+
+    let (a, b) = (data[offset] as u16, data[offset + 1] as u16);
+
+    Some(if big_endian { (a << 8) | b } else { (b << 8) | a })
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    match (read_u16(data, offset, big_endian), read_u16(data, offset + 2, big_endian)) {
+        (Some(first), Some(second)) if big_endian => Some(((first as u32) << 16) | second as u32),
+        (Some(first), Some(second)) => Some(((second as u32) << 16) | first as u32),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use usb::hid::*;
+    use usb::record::*;
+    use dissect::*;
+    use ::U2fDevice;
+
+    fn recorded_session() -> Vec<RecordedReport> {
+        let soft = SoftHidTransport::new(SoftAuthenticator::new(&[7; 32]));
+
+        let mut device = U2fHidDevice::new(RecordingTransport::new(soft, vec![]));
+        device.init().unwrap();
+        device.get_version().unwrap();
+        assert!(device.authenticate(&[1; 32], &[2; 32], &[3; 64]).is_err());
+        let (_, log) = device.hid_device.into_inner();
+
+        String::from_utf8(log).unwrap().lines()
+            .map(|line| RecordedReport::parse_line(line).unwrap())
+            .collect()
+    }
+
+    fn push_u16(out: &mut Vec<u8>, value: u16) {
+        out.push(value as u8);
+        out.push((value >> 8) as u8);
+    }
+
+    fn push_u32(out: &mut Vec<u8>, value: u32) {
+        push_u16(out, value as u16);
+        push_u16(out, (value >> 16) as u16);
+    }
+
+    /// A usbmon frame as the kernel hands it out for `report`, little endian.
+    fn usbmon_frame(report: &RecordedReport) -> Vec<u8> {
+        let mut frame = vec![0; 8];
+        let (event, endpoint) = match report.direction {
+            Direction::Write => (b'S', 0x02),
+            Direction::Read => (b'C', 0x81),
+        };
+
+        frame.extend_from_slice(&[event, USB_TRANSFER_INTERRUPT, endpoint, 5]);
+        push_u16(&mut frame, 3);
+        frame.extend_from_slice(&[0; 18]);
+        push_u32(&mut frame, report.data.len() as u32);
+        push_u32(&mut frame, report.data.len() as u32);
+        frame.extend_from_slice(&[0; 8]);
+        frame.extend_from_slice(&report.data);
+
+        frame
+    }
+
+    fn pcap(reports: &[RecordedReport]) -> Vec<u8> {
+        let mut out = vec![];
+        push_u32(&mut out, PCAP_MAGIC_MICROS);
+        push_u16(&mut out, 2);
+        push_u16(&mut out, 4);
+        out.extend_from_slice(&[0; 12]);
+        push_u32(&mut out, LINKTYPE_USB_LINUX);
+
+        for (n, report) in reports.iter().enumerate() {
+            let frame = usbmon_frame(report);
+
+            push_u32(&mut out, 1000);
+            push_u32(&mut out, n as u32 * 2000);
+            push_u32(&mut out, frame.len() as u32);
+            push_u32(&mut out, frame.len() as u32);
+            out.extend_from_slice(&frame);
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_pcap_transactions() {
+        let recorded = recorded_session();
+        let captured = parse_capture(&pcap(&recorded)).unwrap();
+
+        assert_eq!(captured.len(), recorded.len());
+        assert_eq!((captured[1].bus, captured[1].device, captured[1].report.elapsed_millis), (3, 5, 2));
+        assert_eq!(captured.iter().map(|c| c.report.data.clone()).collect::<Vec<Vec<u8>>>(),
+                   recorded.iter().map(|r| r.data.clone()).collect::<Vec<Vec<u8>>>());
+
+        let reports = captured.into_iter().map(|c| c.report).collect::<Vec<RecordedReport>>();
+        let transactions = transactions(&reports, HID_REPORT_SIZE);
+
+        assert_eq!(transactions.len(), 3);
+        assert!(transactions.iter().all(|t| t.request.is_some() && t.response.is_some()));
+
+        match transactions[1].response {
+            Some(Message { payload: MessagePayload::ApduResponse { u2f: Some(U2fPayload::VersionResponse(ref version)), .. }, .. }) => {
+                assert_eq!(version, "U2F_V2");
+            },
+            ref other => panic!("unexpected response: {:?}", other),
+        }
+
+        // the foreign key handle is rejected
+        assert!(!transactions[1].is_error());
+        assert!(transactions[2].is_error());
+    }
+
+    /// A pcapng capture of `reports` on one interface with the given
+    /// `if_tsresol`, the nth report stamped `first + n * step`.
+    fn pcapng(reports: &[RecordedReport], tsresol: u8, first: u64, step: u64) -> Vec<u8> {
+        let mut out = vec![];
+
+        push_u32(&mut out, PCAPNG_SECTION_HEADER);
+        push_u32(&mut out, 28);
+        push_u32(&mut out, PCAPNG_BYTE_ORDER_MAGIC);
+        push_u16(&mut out, 1);
+        push_u16(&mut out, 0);
+        out.extend_from_slice(&[0xff; 8]);
+        push_u32(&mut out, 28);
+
+        push_u32(&mut out, PCAPNG_INTERFACE_DESCRIPTION);
+        push_u32(&mut out, 32);
+        push_u16(&mut out, LINKTYPE_USB_LINUX as u16);
+        push_u16(&mut out, 0);
+        push_u32(&mut out, 0);
+        push_u16(&mut out, PCAPNG_OPTION_TSRESOL);
+        push_u16(&mut out, 1);
+        out.extend_from_slice(&[tsresol, 0, 0, 0]);
+        push_u32(&mut out, 0);
+        push_u32(&mut out, 32);
+
+        for (n, report) in reports.iter().enumerate() {
+            let mut frame = usbmon_frame(report);
+            let captured = frame.len() as u32;
+
+            while frame.len() % 4 != 0 {
+                frame.push(0);
+            }
+
+            let timestamp = first + n as u64 * step;
+            let len = 32 + frame.len() as u32;
+
+            push_u32(&mut out, PCAPNG_ENHANCED_PACKET);
+            push_u32(&mut out, len);
+            push_u32(&mut out, 0);
+            push_u32(&mut out, (timestamp >> 32) as u32);
+            push_u32(&mut out, timestamp as u32);
+            push_u32(&mut out, captured);
+            push_u32(&mut out, captured);
+            out.extend_from_slice(&frame);
+            push_u32(&mut out, len);
+        }
+
+        out
+    }
+
+    #[test]
+    fn test_pcapng_with_nanosecond_timestamps() {
+        let recorded = recorded_session();
+
+        let captured = parse_capture(&pcapng(&recorded, 9, 5000000000, 3000000)).unwrap();
+
+        assert_eq!(captured.len(), recorded.len());
+        assert_eq!(captured[2].report.elapsed_millis, 6);
+        assert_eq!(captured[2].report.data, recorded[2].data);
+    }
+
+    #[test]
+    fn test_pcapng_hostile_timestamp_resolution() {
+        let recorded = recorded_session();
+
+        // 10^20 and 2^127 units per second do not fit in a u64
+        for &tsresol in [20, 0xff].iter() {
+            match parse_capture(&pcapng(&recorded, tsresol, 0, 1)) {
+                Err(Error(ErrorKind::InvalidCapture(_), _)) => {},
+                other => panic!("unexpected result for {:#x}: {:?}", tsresol, other),
+            }
+        }
+
+        // whole seconds, too many of them to count in microseconds
+        match parse_capture(&pcapng(&recorded, 0, 1 << 60, 1)) {
+            Err(Error(ErrorKind::InvalidCapture(_), _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // 2^63 units per second, stamped just short of two seconds
+        let captured = parse_capture(&pcapng(&recorded, 0xbf, ::std::u64::MAX - recorded.len() as u64, 1)).unwrap();
+
+        assert_eq!(captured.len(), recorded.len());
+        assert_eq!(captured[0].report.elapsed_millis, 0);
+    }
+}
@@ -0,0 +1,376 @@
+//! Recording and replaying HID sessions.
+//!
+//! `RecordingTransport` logs every report that passes through it, one per
+//! line as `<elapsed millis> <W|R> <hex>`, where `W` is a report written to
+//! the device and `R` one read from it. A read that timed out is logged as
+//! `<elapsed millis> T` and kept as a `Read` with no data. `ReplayTransport`
+//! plays such a log back, checking that the client writes exactly the
+//! recorded reports. Timing is recorded for reference only; replay does not
+//! wait.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::error::*;
+use super::hid::*;
+use super::transport::*;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Direction {
+    Write,
+    Read,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedReport {
+    pub elapsed_millis: u64,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+impl RecordedReport {
+    pub fn is_timeout(&self) -> bool {
+        self.direction == Direction::Read && self.data.is_empty()
+    }
+
+    pub fn to_line(&self) -> String {
+        if self.is_timeout() {
+            return format!("{} T", self.elapsed_millis);
+        }
+
+        let direction = match self.direction {
+            Direction::Write => "W",
+            Direction::Read => "R",
+        };
+
+        format!("{} {} {}", self.elapsed_millis, direction, to_hex(&self.data))
+    }
+
+    pub fn parse_line(line: &str) -> Option<RecordedReport> {
+        let mut parts = line.split_whitespace();
+
+        let elapsed_millis = parts.next().and_then(|part| part.parse().ok());
+        let (direction, data) = match parts.next() {
+            Some("W") => (Some(Direction::Write), parts.next().and_then(from_hex)),
+            Some("R") => (Some(Direction::Read), parts.next().and_then(from_hex)),
+            Some("T") => (Some(Direction::Read), Some(vec![])),
+            _ => (None, None),
+        };
+
+        match (elapsed_millis, direction, data, parts.next()) {
+            (Some(elapsed_millis), Some(direction), Some(data), None) => Some(RecordedReport {
+                elapsed_millis: elapsed_millis,
+                direction: direction,
+                data: data,
+            }),
+            _ => None,
+        }
+    }
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len() / 2)
+        .map(|i| hex.get(2 * i..2 * i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+/// Passes reports through to `transport`, logging them to `writer`.
+pub struct RecordingTransport<T, W> where W: Write {
+    transport: T,
+    writer: Mutex<W>,
+    started: Instant,
+}
+
+impl <T> RecordingTransport<T, BufWriter<File>> where T: HidTransport {
+    pub fn create<P: AsRef<Path>>(transport: T, path: P) -> Result<RecordingTransport<T, BufWriter<File>>> {
+        Ok(Self::new(transport, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl <T, W> RecordingTransport<T, W> where T: HidTransport, W: Write {
+    pub fn new(transport: T, writer: W) -> RecordingTransport<T, W> {
+        RecordingTransport {
+            transport: transport,
+            writer: Mutex::new(writer),
+            started: Instant::now(),
+        }
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.transport, self.writer.into_inner().expect("recording writer lock"))
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) -> Result<()> {
+        let elapsed = self.started.elapsed();
+
+        let report = RecordedReport {
+            elapsed_millis: elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1000000,
+            direction: direction,
+            data: data.to_owned(),
+        };
+
+        let mut writer = self.writer.lock().expect("recording writer lock");
+        writeln!(writer, "{}", report.to_line())?;
+        writer.flush()?;
+
+        Ok(())
+    }
+}
+
+impl <T, W> HidTransport for RecordingTransport<T, W> where T: HidTransport, W: Write {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let written = self.transport.write_report(report)?;
+        self.record(Direction::Write, report)?;
+
+        Ok(written)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let bytes = self.transport.read_report(report, timeout_millis)?;
+
+        // a poll that finds nothing waiting is not recorded, but a read that
+        // timed out is, as the client's next step depends on it
+        if bytes != 0 || timeout_millis != 0 {
+            self.record(Direction::Read, &report[0..bytes])?;
+        }
+
+        Ok(bytes)
+    }
+}
+
+struct ReplayState {
+    reports: VecDeque<RecordedReport>,
+    position: usize,
+    // (recorded, actual) nonce of the last INIT request
+    init_nonce: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Serves a recorded session back to a client.
+///
+/// INIT requests are matched without their nonce, which is random, and the
+/// client's nonce is substituted into the recorded response.
+pub struct ReplayTransport {
+    state: Mutex<ReplayState>,
+}
+
+impl ReplayTransport {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ReplayTransport> {
+        Self::from_reader(File::open(path)?)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<ReplayTransport> {
+        let mut reports = VecDeque::new();
+
+        for (n, line) in BufReader::new(reader).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match RecordedReport::parse_line(line) {
+                Some(report) => reports.push_back(report),
+                None => bail!(ErrorKind::InvalidRecording(n + 1)),
+            }
+        }
+
+        Ok(ReplayTransport {
+            state: Mutex::new(ReplayState {
+                reports: reports,
+                position: 0,
+                init_nonce: None,
+            }),
+        })
+    }
+
+    /// Fails unless every recorded report has been replayed.
+    pub fn finish(&self) -> Result<()> {
+        let state = self.state.lock().expect("replay state lock");
+
+        if !state.reports.is_empty() {
+            bail!(ErrorKind::ReplayMismatch(state.position));
+        }
+
+        Ok(())
+    }
+
+    fn next(state: &mut ReplayState, direction: Direction) -> Result<RecordedReport> {
+        match state.reports.pop_front() {
+            Some(ref report) if report.direction == direction => {
+                state.position += 1;
+                Ok(report.clone())
+            },
+            _ => bail!(ErrorKind::ReplayMismatch(state.position)),
+        }
+    }
+}
+
+fn is_init(report: &[u8]) -> bool {
+    report.len() >= 15 && report[4] == U2fHidCommand::Init as u8
+}
+
+impl HidTransport for ReplayTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().expect("replay state lock");
+
+        let expected = Self::next(&mut state, Direction::Write)?;
+
+        if is_init(report) && is_init(&expected.data) && report.len() == expected.data.len() {
+            if report[0..7] == expected.data[0..7] && report[15..] == expected.data[15..] {
+                state.init_nonce = Some((expected.data[7..15].to_owned(), report[7..15].to_owned()));
+                return Ok(report.len());
+            }
+        } else if report == &expected.data[..] {
+            return Ok(report.len());
+        }
+
+        bail!(ErrorKind::ReplayMismatch(state.position - 1));
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let mut state = self.state.lock().expect("replay state lock");
+
+        // a poll that finds nothing waiting is not recorded, so it must not
+        // use up a recorded timeout
+        let waiting = match state.reports.front() {
+            Some(report) => report.direction == Direction::Read && !report.is_timeout(),
+            None => false,
+        };
+        if timeout_millis == 0 && !waiting {
+            return Ok(0);
+        }
+
+        // a recorded timeout comes back as an empty read
+        let mut recorded = Self::next(&mut state, Direction::Read)?.data;
+
+        if is_init(&recorded) {
+            if let Some((ref recorded_nonce, ref nonce)) = state.init_nonce {
+                if &recorded[7..15] == &recorded_nonce[..] {
+                    recorded[7..15].copy_from_slice(nonce);
+                }
+            }
+        }
+
+        let len = ::std::cmp::min(recorded.len(), report.len());
+        report[0..len].copy_from_slice(&recorded[0..len]);
+
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use bytebuffer::*;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+    use ::{U2fDevice, U2fVersion};
+
+    fn session<T: HidTransport>(device: &mut U2fHidDevice<T>, challenge: &[u8]) -> ::error::Result<(U2fVersion, ::RegisterResponse, ::AuthenticateResponse)> {
+        device.init()?;
+
+        let version = device.get_version()?;
+        let registration = device.register(challenge, &[2; 32])?;
+        let authentication = device.authenticate(challenge, &[2; 32], &registration.key_handle)?;
+
+        Ok((version, registration, authentication))
+    }
+
+    #[test]
+    fn test_replay_recorded_session() {
+        let soft = SoftHidTransport::new(SoftAuthenticator::new(&[7; 32]));
+
+        let mut device = U2fHidDevice::new(RecordingTransport::new(soft, vec![]));
+        let recorded = session(&mut device, &[1; 32]).unwrap();
+        let (_, log) = device.hid_device.into_inner();
+
+        let replay = ReplayTransport::from_reader(&log[..]).unwrap();
+        let mut device = U2fHidDevice::new(replay);
+
+        assert_eq!(session(&mut device, &[1; 32]).unwrap(), recorded);
+        device.hid_device.finish().unwrap();
+
+        // a client that diverges from the recording is caught
+        let mut device = U2fHidDevice::new(ReplayTransport::from_reader(&log[..]).unwrap());
+        assert!(session(&mut device, &[3; 32]).is_err());
+    }
+
+    fn keepalive_session<T: HidTransport>(device: &mut U2fHidDevice<T>) -> Result<(Vec<u8>, Vec<KeepaliveStatus>)> {
+        let statuses = Arc::new(Mutex::new(vec![]));
+        let reported = statuses.clone();
+        device.set_keepalive_callback(move |status| reported.lock().unwrap().push(status));
+
+        let response = device.message(&[1, 2, 3])?;
+        let statuses = statuses.lock().unwrap().clone();
+
+        Ok((response, statuses))
+    }
+
+    #[test]
+    fn test_replay_keepalives_and_timeouts() {
+        let (host, token) = MemoryTransport::pair();
+
+        let mut device = U2fHidDevice::new(RecordingTransport::new(host, vec![]));
+        device.channel_id = 0x01020304;
+
+        // a slow token, which the client waits out over several reads
+        let slow = thread::spawn(move || {
+            let mut token = U2fHidDevice::new(token);
+            token.channel_id = 0x01020304;
+
+            let mut buf = ByteBuffer::new();
+            token.recv_response(U2fHidCommand::Msg, &mut buf).unwrap();
+
+            thread::sleep(Duration::from_millis(250));
+            let mut keepalive = ByteBuffer::from_bytes(&[KeepaliveStatus::Processing as u8]);
+            token.send_request(U2fHidCommand::Keepalive, &mut keepalive).unwrap();
+
+            thread::sleep(Duration::from_millis(250));
+            token.send_request(U2fHidCommand::Msg, &mut buf).unwrap();
+        });
+
+        let recorded = keepalive_session(&mut device).unwrap();
+        slow.join().unwrap();
+        assert_eq!(recorded, (vec![1, 2, 3], vec![KeepaliveStatus::Processing]));
+
+        let (_, log) = device.hid_device.into_inner();
+        assert!(String::from_utf8(log.clone()).unwrap().lines().any(|line| line.ends_with(" T")));
+
+        // the client's reads time out in the same places, without waiting
+        let mut device = U2fHidDevice::new(ReplayTransport::from_reader(&log[..]).unwrap());
+        device.channel_id = 0x01020304;
+
+        assert_eq!(keepalive_session(&mut device).unwrap(), recorded);
+        device.hid_device.finish().unwrap();
+    }
+
+    #[test]
+    fn test_parse_line() {
+        let report = RecordedReport::parse_line("12 W 0102ff").unwrap();
+
+        assert_eq!(report, RecordedReport { elapsed_millis: 12, direction: Direction::Write, data: vec![1, 2, 0xff] });
+        assert_eq!(report.to_line(), "12 W 0102ff");
+        assert!(RecordedReport::parse_line("12 X 0102ff").is_none());
+        assert!(RecordedReport::parse_line("12 R 010").is_none());
+
+        let timeout = RecordedReport::parse_line("40 T").unwrap();
+        assert_eq!(timeout, RecordedReport { elapsed_millis: 40, direction: Direction::Read, data: vec![] });
+        assert_eq!(timeout.to_line(), "40 T");
+        assert!(RecordedReport::parse_line("40 T 01").is_none());
+    }
+}
@@ -0,0 +1,262 @@
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+use std::time::Duration;
+use std::cmp;
+
+use super::error::*;
+#[cfg(feature = "hidapi")]
+use hidapi::{HidApi, HidDevice, HidDeviceInfo};
+#[cfg(feature = "hidapi")]
+use owning_ref::OwningHandle;
+#[cfg(feature = "hidapi")]
+use super::FidoExt;
+#[cfg(feature = "hidapi")]
+use super::hid::{U2fHidDevice, HID_REPORT_SIZE};
+
+/// Raw report I/O underneath the U2FHID framing layer.
+///
+/// Reports handed to and returned from a transport never include the
+/// leading HID report number.
+pub trait HidTransport {
+    /// Write a single output report, returning the number of bytes written.
+    fn write_report(&self, report: &[u8]) -> Result<usize>;
+
+    /// Read a single input report, waiting at most `timeout_millis`
+    /// (or forever if negative). Returns 0 if the timeout elapsed.
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize>;
+}
+
+impl <'a, T: ?Sized> HidTransport for &'a T where T: HidTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        (**self).read_report(report, timeout_millis)
+    }
+}
+
+impl <T: ?Sized> HidTransport for Arc<T> where T: HidTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        (**self).write_report(report)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        (**self).read_report(report, timeout_millis)
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl <'a> HidTransport for HidDevice<'a> {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let mut buf = Vec::with_capacity(report.len() + 1);
+        buf.push(0x0); // hid report number
+        buf.extend_from_slice(report);
+
+        // hidapi counts the report number as written
+        let written = self.write(&buf[..])?;
+
+        Ok(written.saturating_sub(1))
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        Ok(self.read_timeout(report, timeout_millis)?)
+    }
+}
+
+/// A shared `HidApi`, from which devices can be opened that own their
+/// handle. Clones refer to the same `HidApi`, so one context can serve the
+/// whole process.
+#[cfg(feature = "hidapi")]
+#[derive(Clone)]
+pub struct HidContext {
+    // open devices hold a clone of the inner Arc, so the HidApi can only be
+    // borrowed mutably while none are open
+    api: Arc<Mutex<Arc<HidApi>>>,
+}
+
+// SAFETY: HidApi is not Send or Sync because hidapi's global state is not
+// thread safe. Every call on the HidApi itself goes through the mutex, so
+// none of them overlap, and it is only ever mutated through Arc::get_mut,
+// so never while an OwnedHidDevice borrows it. Open devices are used on
+// their own threads while the context enumerates or opens others; we rely
+// on hidapi allowing distinct device handles to be used concurrently with
+// each other and with hid_enumerate and hid_open_path.
+#[cfg(feature = "hidapi")]
+unsafe impl Send for HidContext {}
+#[cfg(feature = "hidapi")]
+unsafe impl Sync for HidContext {}
+
+#[cfg(feature = "hidapi")]
+impl HidContext {
+    pub fn new() -> Result<HidContext> {
+        Ok(HidContext {
+            api: Arc::new(Mutex::new(Arc::new(HidApi::new()?))),
+        })
+    }
+
+    /// Re-enumerates the attached devices. Open devices borrow the
+    /// `HidApi`, so this fails with `DevicesOpen` until they are all dropped.
+    pub fn refresh_devices(&self) -> Result<()> {
+        let mut api = self.api.lock().expect("hidapi lock");
+
+        match Arc::get_mut(&mut *api) {
+            Some(api) => {
+                api.refresh_devices();
+                Ok(())
+            },
+            None => bail!(ErrorKind::DevicesOpen),
+        }
+    }
+
+    pub fn open_path(&self, path: &str) -> Result<OwnedHidDevice> {
+        // held while opening so that it does not overlap other HidApi calls
+        let api = self.api.lock().expect("hidapi lock");
+
+        let device = OwningHandle::try_new(api.clone(), |api: *const HidApi| -> Result<Box<HidDevice<'static>>> {
+            // the device borrows the HidApi, which stays put inside the Arc
+            // that the handle keeps alive until after the device is dropped,
+            // and which is not mutated while that Arc is shared
+            let api: &'static HidApi = unsafe { &*api };
+
+            Ok(Box::new(api.open_path(path)?))
+        })?;
+
+        Ok(OwnedHidDevice {
+            device: device,
+        })
+    }
+
+    /// Opens `device` as a U2FHID device, see `hidapi_packet_size`.
+    pub fn open_u2f(&self, device: &HidDeviceInfo) -> Result<U2fHidDevice<OwnedHidDevice>> {
+        self.open_u2f_with_packet_size(device, hidapi_packet_size(device))
+    }
+
+    pub fn open_u2f_with_packet_size(&self, device: &HidDeviceInfo, packet_size: usize) -> Result<U2fHidDevice<OwnedHidDevice>> {
+        U2fHidDevice::with_packet_size(self.open_path(&device.path)?, packet_size)
+    }
+}
+
+/// U2FHID packet size of a device found by hidapi. hidapi does not expose
+/// report descriptors, so this reads it through hidraw where hidapi uses
+/// that backend, and otherwise assumes `HID_REPORT_SIZE`.
+#[cfg(all(feature = "hidapi", target_os = "linux"))]
+pub fn hidapi_packet_size(device: &HidDeviceInfo) -> usize {
+    super::hidraw::Hidraw::new().packet_size(&device.path).unwrap_or(HID_REPORT_SIZE)
+}
+
+#[cfg(all(feature = "hidapi", not(target_os = "linux")))]
+pub fn hidapi_packet_size(_device: &HidDeviceInfo) -> usize {
+    HID_REPORT_SIZE
+}
+
+#[cfg(feature = "hidapi")]
+impl FidoExt for HidContext {
+    type DeviceInfo = HidDeviceInfo;
+
+    fn fido_devices(&self) -> Vec<HidDeviceInfo> {
+        self.api.lock().expect("hidapi lock").fido_devices()
+    }
+}
+
+/// A hidapi device that keeps its `HidContext`'s `HidApi` alive, so that it
+/// can be stored on its own or moved to another thread.
+#[cfg(feature = "hidapi")]
+pub struct OwnedHidDevice {
+    // closes the device before releasing the HidApi it borrows
+    device: OwningHandle<Arc<HidApi>, Box<HidDevice<'static>>>,
+}
+
+// SAFETY: a hidapi device handle is not Send because it wraps a raw
+// hid_device pointer. We rely on hidapi allowing a handle to be used from
+// any thread as long as calls on it do not overlap; OwnedHidDevice is not
+// Sync, so its &self methods cannot run on two threads at once. The
+// Arc<HidApi> it holds is only read through, see HidContext; if it is the
+// last one, the context is gone and nothing else can be using the HidApi
+// when it is dropped.
+#[cfg(feature = "hidapi")]
+unsafe impl Send for OwnedHidDevice {}
+
+#[cfg(feature = "hidapi")]
+impl HidTransport for OwnedHidDevice {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        self.device.write_report(report)
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        self.device.read_report(report, timeout_millis)
+    }
+}
+
+/// One end of an in-memory report channel.
+///
+/// Reports written to one end of a pair are read from the other, which
+/// lets the framing and APDU layers run without a physical device.
+pub struct MemoryTransport {
+    tx: Mutex<Sender<Vec<u8>>>,
+    rx: Mutex<Receiver<Vec<u8>>>,
+}
+
+impl MemoryTransport {
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, b_rx) = channel();
+        let (b_tx, a_rx) = channel();
+
+        let a = MemoryTransport {
+            tx: Mutex::new(a_tx),
+            rx: Mutex::new(a_rx),
+        };
+
+        let b = MemoryTransport {
+            tx: Mutex::new(b_tx),
+            rx: Mutex::new(b_rx),
+        };
+
+        (a, b)
+    }
+}
+
+impl HidTransport for MemoryTransport {
+    fn write_report(&self, report: &[u8]) -> Result<usize> {
+        let tx = self.tx.lock().expect("memory transport lock");
+
+        tx.send(report.to_owned()).map_err(|_| Error::from(ErrorKind::TransportDisconnected))?;
+
+        Ok(report.len())
+    }
+
+    fn read_report(&self, report: &mut [u8], timeout_millis: i32) -> Result<usize> {
+        let rx = self.rx.lock().expect("memory transport lock");
+
+        let received = if timeout_millis < 0 {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(Duration::from_millis(timeout_millis as u64))
+        };
+
+        match received {
+            Ok(data) => {
+                let len = cmp::min(data.len(), report.len());
+                report[0..len].copy_from_slice(&data[0..len]);
+                Ok(len)
+            },
+            Err(RecvTimeoutError::Timeout) => Ok(0),
+            Err(RecvTimeoutError::Disconnected) => Err(ErrorKind::TransportDisconnected.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    #[cfg(feature = "hidapi")]
+    #[test]
+    fn test_owned_device_is_send() {
+        use usb::hid::U2fHidDevice;
+
+        fn assert_send<T: Send>() {}
+
+        assert_send::<super::OwnedHidDevice>();
+        assert_send::<U2fHidDevice<super::OwnedHidDevice>>();
+        assert_send::<super::HidContext>();
+    }
+}
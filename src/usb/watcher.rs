@@ -0,0 +1,332 @@
+//! Arrival and removal events for FIDO devices.
+//!
+//! `DeviceWatcher` re-lists a `DeviceSource` and diffs the result against the
+//! previous list. It polls at `poll_interval`; on Linux it can also wake up as
+//! soon as a hidraw node appears in or disappears from `/dev`.
+
+use std::thread;
+use std::time::{Duration, Instant};
+#[cfg(target_os = "linux")]
+use std::ffi::CString;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::path::Path;
+#[cfg(target_os = "linux")]
+use libc;
+
+#[cfg(feature = "hidapi")]
+use hidapi::*;
+
+use super::error::*;
+use super::FidoExt;
+
+pub const DEFAULT_POLL_INTERVAL_MILLIS: u64 = 1000;
+
+/// Lists the FIDO devices currently attached.
+pub trait DeviceSource {
+    type DeviceInfo: Clone;
+
+    fn devices(&mut self) -> Vec<Self::DeviceInfo>;
+
+    /// Identifies a device across listings, e.g. by its path.
+    fn device_key(&self, device: &Self::DeviceInfo) -> String;
+}
+
+#[cfg(feature = "hidapi")]
+impl DeviceSource for HidApi {
+    type DeviceInfo = HidDeviceInfo;
+
+    fn devices(&mut self) -> Vec<HidDeviceInfo> {
+        self.refresh_devices();
+        self.fido_devices()
+    }
+
+    fn device_key(&self, device: &HidDeviceInfo) -> String {
+        device.path.clone()
+    }
+}
+
+#[cfg(feature = "hidapi")]
+impl DeviceSource for super::transport::HidContext {
+    type DeviceInfo = HidDeviceInfo;
+
+    /// Lists the devices found by the last enumeration that succeeded; no
+    /// new enumeration happens while devices opened from this context are
+    /// still open.
+    fn devices(&mut self) -> Vec<HidDeviceInfo> {
+        let _ = self.refresh_devices();
+        self.fido_devices()
+    }
+
+    fn device_key(&self, device: &HidDeviceInfo) -> String {
+        device.path.clone()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl DeviceSource for super::hidraw::Hidraw {
+    type DeviceInfo = super::hidraw::HidrawDeviceInfo;
+
+    fn devices(&mut self) -> Vec<super::hidraw::HidrawDeviceInfo> {
+        self.fido_devices()
+    }
+
+    fn device_key(&self, device: &super::hidraw::HidrawDeviceInfo) -> String {
+        device.path.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceEvent<D> {
+    Arrived(D),
+    Removed(D),
+}
+
+pub struct DeviceWatcher<S> where S: DeviceSource {
+    source: S,
+    known: Vec<(String, S::DeviceInfo)>,
+    pub poll_interval: Duration,
+    #[cfg(target_os = "linux")]
+    dev_watch: Option<DevWatch>,
+}
+
+impl <S> DeviceWatcher<S> where S: DeviceSource {
+    /// Creates a polling watcher. Devices already attached are reported as
+    /// arrivals by the first `poll`.
+    pub fn new(source: S) -> DeviceWatcher<S> {
+        DeviceWatcher {
+            source: source,
+            known: vec![],
+            poll_interval: Duration::from_millis(DEFAULT_POLL_INTERVAL_MILLIS),
+            #[cfg(target_os = "linux")]
+            dev_watch: None,
+        }
+    }
+
+    /// Creates a watcher that also wakes up on hidraw nodes being created
+    /// or deleted in `dev_root` (usually `/dev`).
+    #[cfg(target_os = "linux")]
+    pub fn with_dev_watch<P: AsRef<Path>>(source: S, dev_root: P) -> Result<DeviceWatcher<S>> {
+        let mut watcher = Self::new(source);
+        watcher.dev_watch = Some(DevWatch::new(dev_root)?);
+
+        Ok(watcher)
+    }
+
+    pub fn source(&self) -> &S {
+        &self.source
+    }
+
+    /// Devices attached as of the last `poll`.
+    pub fn devices(&self) -> Vec<S::DeviceInfo> {
+        self.known.iter().map(|&(_, ref device)| device.clone()).collect()
+    }
+
+    /// Re-lists the devices and returns what changed since the last call.
+    pub fn poll(&mut self) -> Vec<DeviceEvent<S::DeviceInfo>> {
+        let current = self.source.devices()
+            .into_iter()
+            .map(|device| (self.source.device_key(&device), device))
+            .collect::<Vec<(String, S::DeviceInfo)>>();
+
+        let mut events = vec![];
+
+        for &(ref key, ref device) in self.known.iter() {
+            if !current.iter().any(|&(ref k, _)| k == key) {
+                events.push(DeviceEvent::Removed(device.clone()));
+            }
+        }
+
+        for &(ref key, ref device) in current.iter() {
+            if !self.known.iter().any(|&(ref k, _)| k == key) {
+                events.push(DeviceEvent::Arrived(device.clone()));
+            }
+        }
+
+        self.known = current;
+
+        events
+    }
+
+    /// Blocks until devices arrive or leave, or `timeout` passes, in which
+    /// case no events are returned.
+    pub fn wait(&mut self, timeout: Duration) -> Result<Vec<DeviceEvent<S::DeviceInfo>>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let events = self.poll();
+
+            let now = Instant::now();
+
+            if !events.is_empty() || now >= deadline {
+                return Ok(events);
+            }
+
+            let wait = ::std::cmp::min(deadline - now, self.poll_interval);
+
+            self.sleep(wait)?;
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn sleep(&mut self, duration: Duration) -> Result<()> {
+        match self.dev_watch {
+            Some(ref mut dev_watch) => dev_watch.wait(duration),
+            None => {
+                thread::sleep(duration);
+                Ok(())
+            },
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sleep(&mut self, duration: Duration) -> Result<()> {
+        thread::sleep(duration);
+        Ok(())
+    }
+}
+
+/// inotify watch for hidraw nodes coming and going.
+#[cfg(target_os = "linux")]
+struct DevWatch {
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl DevWatch {
+    fn new<P: AsRef<Path>>(dev_root: P) -> Result<DevWatch> {
+        let path = CString::new(dev_root.as_ref().as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        let watch = DevWatch {
+            fd: fd,
+        };
+
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO | libc::IN_ATTRIB;
+
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        Ok(watch)
+    }
+
+    /// Waits up to `timeout` for a change in the directory. Any change
+    /// wakes us up; the watcher re-lists devices to see what it was.
+    fn wait(&mut self, timeout: Duration) -> Result<()> {
+        let mut fds = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+
+        let millis = timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1000000;
+        let ready = unsafe { libc::poll(&mut fds, 1, ::std::cmp::min(millis, i32::max_value() as u64) as i32) };
+
+        if ready < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+
+        // drain the queued events
+        let mut buf = [0u8; 4096];
+        while unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } > 0 {}
+
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for DevWatch {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone)]
+    struct SimulatedSource {
+        devices: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl DeviceSource for SimulatedSource {
+        type DeviceInfo = String;
+
+        fn devices(&mut self) -> Vec<String> {
+            self.devices.lock().unwrap().clone()
+        }
+
+        fn device_key(&self, device: &String) -> String {
+            device.clone()
+        }
+    }
+
+    #[test]
+    fn test_arrival_and_removal() {
+        let source = SimulatedSource { devices: Arc::new(Mutex::new(vec!["/dev/hidraw1".to_owned()])) };
+        let mut watcher = DeviceWatcher::new(source.clone());
+
+        assert_eq!(watcher.poll(), vec![DeviceEvent::Arrived("/dev/hidraw1".to_owned())]);
+        assert_eq!(watcher.poll(), vec![]);
+
+        *source.devices.lock().unwrap() = vec!["/dev/hidraw2".to_owned()];
+
+        assert_eq!(watcher.poll(), vec![
+            DeviceEvent::Removed("/dev/hidraw1".to_owned()),
+            DeviceEvent::Arrived("/dev/hidraw2".to_owned()),
+        ]);
+        assert_eq!(watcher.devices(), vec!["/dev/hidraw2".to_owned()]);
+    }
+
+    #[test]
+    fn test_wait_times_out() {
+        let source = SimulatedSource { devices: Arc::new(Mutex::new(vec![])) };
+        let mut watcher = DeviceWatcher::new(source);
+        watcher.poll_interval = Duration::from_millis(20);
+
+        assert_eq!(watcher.wait(Duration::from_millis(100)).unwrap(), vec![]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dev_watch_wakes_up_on_new_node() {
+        use std::env;
+        use std::fs;
+        use rand;
+
+        let root = env::temp_dir().join(format!("u2f-watcher-test-{}", rand::random::<u32>()));
+        fs::create_dir_all(&root).unwrap();
+
+        let source = SimulatedSource { devices: Arc::new(Mutex::new(vec![])) };
+        let mut watcher = DeviceWatcher::with_dev_watch(source.clone(), &root).unwrap();
+        watcher.poll_interval = Duration::from_secs(60);
+
+        let node = root.join("hidraw3");
+        let plug = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            source.devices.lock().unwrap().push("/dev/hidraw3".to_owned());
+            fs::File::create(&node).unwrap();
+        });
+
+        let start = Instant::now();
+        let events = watcher.wait(Duration::from_secs(10)).unwrap();
+
+        plug.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(events, vec![DeviceEvent::Arrived("/dev/hidraw3".to_owned())]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,309 @@
+//! A thread that owns a device and runs commands on it one at a time.
+//!
+//! Any number of `WorkerHandle`s can queue commands for the same token;
+//! they run in order on the worker's thread, so callers never interleave
+//! their traffic on the device's channel. When a command fails in a way
+//! that may have left the channel out of step, the channel is resynchronised
+//! before the next one.
+
+use std::sync::mpsc::{channel, Sender, Receiver};
+use std::thread;
+use std::thread::JoinHandle;
+use futures::Future;
+use futures::sync::oneshot;
+
+use usb;
+use usb::hid::*;
+use usb::transport::*;
+use error::*;
+use ::{with_user_presence, U2fDevice, U2fVersion, RegisterResponse, AuthenticateResponse};
+
+trait Job<T>: Send {
+    /// Runs the job, returning whether the device needs resynchronising.
+    fn run(&mut self, device: &mut U2fHidDevice<T>) -> bool;
+
+    /// Reports that the job could not be run.
+    fn fail(&mut self, e: Error);
+}
+
+struct Call<R, F> {
+    f: Option<F>,
+    reply: Option<oneshot::Sender<Result<R>>>,
+}
+
+impl <T, R, F> Job<T> for Call<R, F> where R: Send, F: FnOnce(&mut U2fHidDevice<T>) -> Result<R> + Send {
+    fn run(&mut self, device: &mut U2fHidDevice<T>) -> bool {
+        let f = self.f.take().expect("job already run");
+        let result = f(device);
+
+        let stale = match result {
+            Err(ref e) => needs_resync(e),
+            Ok(_) => false,
+        };
+
+        if let Some(reply) = self.reply.take() {
+            // the caller may have given up waiting
+            let _ = reply.send(result);
+        }
+
+        stale
+    }
+
+    fn fail(&mut self, e: Error) {
+        if let Some(reply) = self.reply.take() {
+            let _ = reply.send(Err(e));
+        }
+    }
+}
+
+enum Message<T> {
+    Job(Box<Job<T>>),
+    Stop,
+}
+
+/// Whether `e` may have left the device's channel in an unknown state.
+fn needs_resync(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::HidError(ref kind) => match *kind {
+            usb::error::ErrorKind::HidPacketTooSmall |
+            usb::error::ErrorKind::HidError(_) |
+            usb::error::ErrorKind::HidUnknownError(_) => true,
+            ref kind => kind.needs_resync(),
+        },
+        ErrorKind::Timeout => true,
+        _ => false,
+    }
+}
+
+pub struct DeviceWorker<T> {
+    handle: WorkerHandle<T>,
+    thread: Option<JoinHandle<U2fHidDevice<T>>>,
+}
+
+impl <T> DeviceWorker<T> where T: HidTransport + Send + 'static {
+    /// Takes over `device`, initialising it first if it hasn't been.
+    pub fn spawn(device: U2fHidDevice<T>) -> DeviceWorker<T> {
+        let (sender, receiver) = channel();
+        let cancel_handle = device.cancel_handle();
+
+        let thread = thread::spawn(move || Self::run(device, receiver));
+
+        DeviceWorker {
+            handle: WorkerHandle {
+                sender: sender,
+                cancel_handle: cancel_handle,
+            },
+            thread: Some(thread),
+        }
+    }
+
+    /// A handle for queueing commands, which can be cloned and sent to other
+    /// threads.
+    pub fn handle(&self) -> WorkerHandle<T> {
+        self.handle.clone()
+    }
+
+    /// Stops the worker once the command in progress has finished and hands
+    /// the device back. Commands still queued fail with `WorkerStopped`.
+    pub fn stop(mut self) -> U2fHidDevice<T> {
+        let _ = self.handle.sender.send(Message::Stop);
+
+        self.thread.take().expect("worker thread").join().expect("worker thread")
+    }
+
+    fn run(mut device: U2fHidDevice<T>, receiver: Receiver<Message<T>>) -> U2fHidDevice<T> {
+        let mut stale = device.channel_id == BROADCAST_CID;
+
+        for message in receiver.iter() {
+            let mut job = match message {
+                Message::Job(job) => job,
+                Message::Stop => break,
+            };
+
+            if stale {
+                if let Err(e) = device.resync() {
+                    job.fail(e.into());
+                    continue;
+                }
+            }
+
+            stale = job.run(&mut device);
+        }
+
+        device
+    }
+}
+
+impl <T> Drop for DeviceWorker<T> {
+    fn drop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.handle.sender.send(Message::Stop);
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Queues commands for a `DeviceWorker`.
+pub struct WorkerHandle<T> {
+    sender: Sender<Message<T>>,
+    cancel_handle: CancelHandle,
+}
+
+impl <T> Clone for WorkerHandle<T> {
+    fn clone(&self) -> WorkerHandle<T> {
+        WorkerHandle {
+            sender: self.sender.clone(),
+            cancel_handle: self.cancel_handle.clone(),
+        }
+    }
+}
+
+impl <T> WorkerHandle<T> where T: HidTransport + 'static {
+    /// Queues `f` to run on the device and returns a future for its result.
+    pub fn submit<R, F>(&self, f: F) -> Box<Future<Item=R, Error=Error> + Send>
+        where R: Send + 'static, F: FnOnce(&mut U2fHidDevice<T>) -> Result<R> + Send + 'static
+    {
+        let (reply, result) = oneshot::channel();
+
+        let job = Call {
+            f: Some(f),
+            reply: Some(reply),
+        };
+
+        if self.sender.send(Message::Job(Box::new(job))).is_err() {
+            return Box::new(::futures::future::err(ErrorKind::WorkerStopped.into()));
+        }
+
+        Box::new(result.then(|result| match result {
+            Ok(result) => result,
+            Err(_) => Err(ErrorKind::WorkerStopped.into()),
+        }))
+    }
+
+    /// Runs `f` on the device, blocking until it has.
+    pub fn call<R, F>(&self, f: F) -> Result<R>
+        where R: Send + 'static, F: FnOnce(&mut U2fHidDevice<T>) -> Result<R> + Send + 'static
+    {
+        self.submit(f).wait()
+    }
+
+    /// Cancels the command the worker is running, if any.
+    pub fn cancel(&self) {
+        self.cancel_handle.cancel();
+    }
+
+    pub fn ping(&self) -> Box<Future<Item=(), Error=Error> + Send> {
+        self.submit(|device| Ok(device.ping()?))
+    }
+
+    pub fn wink(&self) -> Box<Future<Item=(), Error=Error> + Send> {
+        self.submit(|device| Ok(device.wink()?))
+    }
+
+    pub fn get_version(&self) -> Box<Future<Item=U2fVersion, Error=Error> + Send> {
+        self.submit(|device| device.get_version())
+    }
+
+    /// Registers once the user touches the device. Other commands wait
+    /// until then.
+    pub fn register(&self, challenge_param: &[u8], application_param: &[u8]) -> Box<Future<Item=RegisterResponse, Error=Error> + Send> {
+        let challenge_param = challenge_param.to_owned();
+        let application_param = application_param.to_owned();
+
+        self.submit(move |device| {
+            with_user_presence(device.timeouts.user_presence, || device.register(&challenge_param, &application_param))
+        })
+    }
+
+    /// Authenticates once the user touches the device. Other commands wait
+    /// until then.
+    pub fn authenticate(&self, challenge_param: &[u8], application_param: &[u8], key_handle: &[u8]) -> Box<Future<Item=AuthenticateResponse, Error=Error> + Send> {
+        let challenge_param = challenge_param.to_owned();
+        let application_param = application_param.to_owned();
+        let key_handle = key_handle.to_owned();
+
+        self.submit(move |device| {
+            with_user_presence(device.timeouts.user_presence, || device.authenticate(&challenge_param, &application_param, &key_handle))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use soft::*;
+    use std::sync::Arc;
+    use ::Verify;
+
+    fn worker() -> (Arc<SoftHidTransport>, DeviceWorker<Arc<SoftHidTransport>>) {
+        let transport = Arc::new(SoftHidTransport::new(SoftAuthenticator::new(&[7; 32])));
+
+        (transport.clone(), DeviceWorker::spawn(U2fHidDevice::new(transport)))
+    }
+
+    #[test]
+    fn test_callers_on_several_threads() {
+        let (_, worker) = worker();
+
+        let callers = (0..4u8).map(|i| {
+            let handle = worker.handle();
+
+            thread::spawn(move || {
+                let challenge = vec![i; 32];
+                let application = vec![2; 32];
+
+                let registration = handle.register(&challenge, &application).wait().unwrap();
+                registration.verify(&challenge, &application).unwrap();
+
+                handle.authenticate(&challenge, &application, &registration.key_handle).wait().unwrap()
+            })
+        }).collect::<Vec<JoinHandle<AuthenticateResponse>>>();
+
+        let mut counters = callers.into_iter()
+            .map(|caller| caller.join().unwrap().counter)
+            .collect::<Vec<u32>>();
+        counters.sort();
+
+        // every authentication ran on its own, in some order
+        assert_eq!(counters, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_resync_after_framing_error() {
+        let (_, worker) = worker();
+        let handle = worker.handle();
+
+        let channel_id = handle.call(|device| Ok(device.channel_id)).unwrap();
+
+        // gives up on a request, leaving its response behind
+        let result: Result<()> = handle.call(|device| {
+            device.send_request(U2fHidCommand::Ping, &mut ::bytebuffer::ByteBuffer::from_bytes(&[1, 2, 3]))?;
+            Err(usb::error::Error::from(usb::error::ErrorKind::UnexpectedPacket).into())
+        });
+        assert!(result.is_err());
+
+        let recovered_channel_id = handle.call(|device| {
+            device.ping()?;
+            Ok(device.channel_id)
+        }).unwrap();
+
+        // synchronised in place rather than allocating a new channel
+        assert_eq!(recovered_channel_id, channel_id);
+    }
+
+    #[test]
+    fn test_stop_returns_device() {
+        let (_, worker) = worker();
+        let handle = worker.handle();
+
+        assert_eq!(handle.get_version().wait().unwrap(), U2fVersion::V2);
+
+        let device = worker.stop();
+        assert!(device.channel_id != BROADCAST_CID);
+
+        match handle.ping().wait() {
+            Err(Error(ErrorKind::WorkerStopped, _)) => {},
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}